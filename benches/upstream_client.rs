@@ -0,0 +1,64 @@
+//! Compares proxying small requests with a fresh `awc::Client` per request
+//! against the shared client built by `proxy::upstream::client`.
+//!
+//! Run with `cargo bench --bench upstream_client`.
+
+use std::time::Instant;
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+
+#[allow(dead_code)]
+#[path = "../src/settings.rs"]
+mod settings;
+
+#[path = "../src/proxy/upstream.rs"]
+mod upstream;
+
+const REQUESTS: u32 = 2_000;
+
+async fn run(name: &str, url: &str, mut client_for_request: impl FnMut() -> awc::Client) {
+    let started_at = Instant::now();
+    for _ in 0..REQUESTS {
+        let mut resp = client_for_request()
+            .get(url)
+            .send()
+            .await
+            .expect("request failed");
+        resp.body().await.expect("body failed");
+    }
+    let elapsed = started_at.elapsed();
+    println!(
+        "{name:<16} {REQUESTS} requests in {elapsed:>10.2?} ({:>8.0} req/s)",
+        f64::from(REQUESTS) / elapsed.as_secs_f64()
+    );
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let server = HttpServer::new(|| {
+        App::new().default_service(web::to(|| async { HttpResponse::Ok().body("ok") }))
+    })
+    .workers(1)
+    .disable_signals()
+    .bind(("127.0.0.1", 0))?;
+    let addr = server.addrs()[0];
+    let server = server.run();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+    let url = format!("http://{addr}/");
+
+    run("fresh client", &url, awc::Client::new).await;
+
+    // The defaults of conf/default.json.
+    let shared = upstream::client(&settings::Upstream {
+        pool_size: 100,
+        keep_alive_secs: 15,
+        conn_lifetime_secs: 75,
+        timeout_secs: 10,
+        idle_timeout_secs: 60,
+    });
+    run("shared client", &url, || shared.clone()).await;
+
+    handle.stop(true).await;
+    Ok(())
+}
@@ -0,0 +1,3 @@
+ALTER TABLE connections
+    ADD COLUMN basic_auth_username      TEXT,
+    ADD COLUMN basic_auth_password_hash TEXT;
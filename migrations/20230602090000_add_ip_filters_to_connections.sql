@@ -0,0 +1,3 @@
+ALTER TABLE connections
+    ADD COLUMN allowed_cidrs TEXT[] NOT NULL DEFAULT '{}',
+    ADD COLUMN denied_cidrs  TEXT[] NOT NULL DEFAULT '{}';
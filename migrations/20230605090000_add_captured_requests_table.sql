@@ -0,0 +1,19 @@
+ALTER TABLE connections
+    ADD COLUMN capture_requests BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE captured_requests
+(
+    id                     uuid PRIMARY KEY,
+    connection_id          uuid        NOT NULL REFERENCES connections (id) ON DELETE CASCADE,
+    method                 TEXT        NOT NULL,
+    path                   TEXT        NOT NULL,
+    request_headers        JSONB       NOT NULL,
+    request_body           BYTEA       NOT NULL,
+    request_body_truncated BOOLEAN     NOT NULL,
+    status                 INTEGER,
+    response_headers       JSONB,
+    duration_ms            BIGINT      NOT NULL,
+    created_at             TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX index_captured_requests_on_connection_id_and_created_at ON captured_requests (connection_id, created_at);
@@ -0,0 +1,2 @@
+ALTER TABLE connections
+    ADD COLUMN header_rules JSONB NOT NULL DEFAULT '[]';
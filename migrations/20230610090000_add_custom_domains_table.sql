@@ -0,0 +1,8 @@
+CREATE TABLE custom_domains
+(
+    id            uuid PRIMARY KEY,
+    connection_id uuid NOT NULL REFERENCES connections (id) ON DELETE CASCADE,
+    hostname      TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX index_custom_domains_on_hostname ON custom_domains (hostname);
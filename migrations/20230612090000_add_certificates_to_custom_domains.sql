@@ -0,0 +1,3 @@
+ALTER TABLE custom_domains
+    ADD COLUMN certificate_pem TEXT,
+    ADD COLUMN private_key_pem TEXT;
@@ -0,0 +1,15 @@
+CREATE TABLE acme_accounts
+(
+    directory_url TEXT PRIMARY KEY,
+    credentials   JSONB NOT NULL
+);
+
+CREATE TABLE acme_challenges
+(
+    token             TEXT PRIMARY KEY,
+    key_authorization TEXT        NOT NULL,
+    created_at        TIMESTAMPTZ NOT NULL
+);
+
+ALTER TABLE custom_domains
+    ADD COLUMN certificate_expires_at TIMESTAMPTZ;
@@ -0,0 +1,11 @@
+CREATE TABLE routes
+(
+    id            uuid PRIMARY KEY,
+    connection_id uuid    NOT NULL REFERENCES connections (id) ON DELETE CASCADE,
+    hostname      TEXT    NOT NULL,
+    path_prefix   TEXT    NOT NULL,
+    strip_prefix  BOOLEAN NOT NULL DEFAULT FALSE,
+    position      INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE UNIQUE INDEX index_routes_on_hostname_and_path_prefix ON routes (hostname, path_prefix);
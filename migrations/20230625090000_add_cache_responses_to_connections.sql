@@ -0,0 +1,2 @@
+ALTER TABLE connections
+    ADD COLUMN cache_responses BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+ALTER TABLE connections
+    ADD COLUMN compression JSONB NOT NULL DEFAULT '{"mode": "passthrough", "content_types": []}';
@@ -0,0 +1,2 @@
+ALTER TABLE connections
+    ADD COLUMN max_body_size BIGINT;
@@ -0,0 +1,2 @@
+ALTER TABLE connections
+    ADD COLUMN rate_limit JSONB;
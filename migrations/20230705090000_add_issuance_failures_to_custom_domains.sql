@@ -0,0 +1,3 @@
+ALTER TABLE custom_domains
+    ADD COLUMN issuance_failures INT NOT NULL DEFAULT 0,
+    ADD COLUMN issuance_failed_at TIMESTAMPTZ;
@@ -0,0 +1,47 @@
+use crate::errors::AppResponse;
+use crate::forwards::ForwardRegistry;
+use crate::proxy::basic_auth::VerifiedCredentials;
+use crate::proxy::cache::ResponseCache;
+use crate::proxy::controller::process;
+use crate::proxy::rate_limit::RateLimiter;
+use crate::settings::Settings;
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::PgPool;
+
+use super::models::AcmeChallenge;
+
+/// Answers HTTP-01 challenges for pending orders. Unknown tokens are handed to
+/// the tunnel so applications can still serve their own challenge files.
+#[allow(clippy::future_not_send, clippy::too_many_arguments)]
+pub async fn challenge(
+    req: HttpRequest,
+    payload: web::Payload,
+    client: web::Data<awc::Client>,
+    registry: web::Data<ForwardRegistry>,
+    cache: web::Data<ResponseCache>,
+    rate_limiter: web::Data<RateLimiter>,
+    verified_credentials: web::Data<VerifiedCredentials>,
+    db: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    path: web::Path<String>,
+) -> AppResponse {
+    match AcmeChallenge::get(&db, &path.into_inner()).await {
+        Ok(challenge) => Ok(HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(challenge.key_authorization)),
+        Err(_) => {
+            process(
+                req,
+                payload,
+                client,
+                registry,
+                cache,
+                rate_limiter,
+                verified_credentials,
+                db,
+                settings,
+            )
+            .await
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod controller;
+mod models;
+pub mod renewer;
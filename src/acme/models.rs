@@ -0,0 +1,77 @@
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::types::Json;
+use sqlx::{FromRow, PgPool, Result};
+
+#[derive(FromRow)]
+pub struct AcmeAccount {
+    pub directory_url: String,
+    pub credentials: Json<serde_json::Value>,
+}
+
+impl AcmeAccount {
+    pub async fn insert(&self, pool: &PgPool) -> Result<()> {
+        // language=PostgreSQL
+        sqlx::query("INSERT INTO acme_accounts (directory_url, credentials) VALUES ($1, $2)")
+            .bind(&self.directory_url)
+            .bind(&self.credentials)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(pool: &PgPool, directory_url: &str) -> Result<Option<Self>> {
+        // language=PostgreSQL
+        sqlx::query_as("SELECT * FROM acme_accounts WHERE directory_url = $1")
+            .bind(directory_url)
+            .fetch_optional(pool)
+            .await
+    }
+}
+
+#[derive(FromRow)]
+pub struct AcmeChallenge {
+    pub token: String,
+    pub key_authorization: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AcmeChallenge {
+    pub fn new(token: String, key_authorization: String) -> Self {
+        Self {
+            token,
+            key_authorization,
+            created_at: Utc::now(),
+        }
+    }
+
+    pub async fn insert(&self, pool: &PgPool) -> Result<()> {
+        // language=PostgreSQL
+        sqlx::query("INSERT INTO acme_challenges (token, key_authorization, created_at) VALUES ($1, $2, $3) ON CONFLICT (token) DO UPDATE SET key_authorization = $2, created_at = $3")
+            .bind(&self.token)
+            .bind(&self.key_authorization)
+            .bind(self.created_at)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get(pool: &PgPool, token: &str) -> Result<Self> {
+        // language=PostgreSQL
+        sqlx::query_as("SELECT * FROM acme_challenges WHERE token = $1")
+            .bind(token)
+            .fetch_one(pool)
+            .await
+    }
+
+    pub async fn delete(&self, pool: &PgPool) -> Result<()> {
+        // language=PostgreSQL
+        sqlx::query("DELETE FROM acme_challenges WHERE token = $1")
+            .bind(&self.token)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, HttpClient, Identifier,
+    NewAccount, NewOrder, Order, OrderStatus,
+};
+use rcgen::{Certificate, CertificateParams, DistinguishedName};
+use sqlx::types::chrono::{self, DateTime, Utc};
+use sqlx::types::Json;
+use sqlx::PgPool;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+use crate::domains::models::CustomDomain;
+use crate::settings::{Acme, Settings};
+use crate::tls::certificate_expiry;
+
+use super::models::{AcmeAccount, AcmeChallenge};
+
+const MAX_POLL_ATTEMPTS: u32 = 10;
+/// Longest wait before retrying a domain whose issuance keeps failing.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(24 * 3600);
+
+/// HTTP client trusting only the CAs of `ca_bundle_path`, such as the one of
+/// a Pebble test server.
+fn http_client(ca_bundle_path: &str) -> Result<Box<dyn HttpClient>> {
+    let ca_bundle = std::fs::read(ca_bundle_path)
+        .with_context(|| format!("Failed to read ACME CA bundle {ca_bundle_path}"))?;
+    let mut roots = rustls::RootCertStore::empty();
+    for certificate in rustls_pemfile::certs(&mut &*ca_bundle)? {
+        roots
+            .add(&rustls::Certificate(certificate))
+            .map_err(|e| anyhow!("Invalid certificate in {ca_bundle_path}: {e}"))?;
+    }
+    if roots.is_empty() {
+        bail!("No certificate found in {ca_bundle_path}");
+    }
+    let tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_only()
+        .enable_http1()
+        .build();
+    Ok(Box::new(hyper::Client::builder().build(connector)))
+}
+
+/// Loads the account registered with the configured directory, creating it on
+/// first use.
+async fn account(acme: &Acme, db: &PgPool) -> Result<Account> {
+    let http = acme
+        .ca_bundle_path
+        .as_deref()
+        .map(http_client)
+        .transpose()?;
+    if let Some(stored) = AcmeAccount::get(db, &acme.directory_url).await? {
+        let credentials: AccountCredentials = serde_json::from_value(stored.credentials.0)?;
+        return Ok(match http {
+            Some(http) => Account::from_credentials_and_http(credentials, http)?,
+            None => Account::from_credentials(credentials)?,
+        });
+    }
+
+    let contact = acme
+        .contact_email
+        .iter()
+        .map(|email| format!("mailto:{email}"))
+        .collect::<Vec<_>>();
+    let contact = contact.iter().map(String::as_str).collect::<Vec<_>>();
+    let new_account = NewAccount {
+        contact: &contact,
+        terms_of_service_agreed: true,
+        only_return_existing: false,
+    };
+    let created = match http {
+        Some(http) => {
+            Account::create_with_http(&new_account, &acme.directory_url, None, http).await
+        }
+        None => Account::create(&new_account, &acme.directory_url, None).await,
+    };
+    let (account, credentials) = created
+        .with_context(|| format!("Failed to create ACME account on {}", acme.directory_url))?;
+    AcmeAccount {
+        directory_url: acme.directory_url.clone(),
+        credentials: Json(serde_json::to_value(&credentials)?),
+    }
+    .insert(db)
+    .await?;
+    info!("registered ACME account on {}", acme.directory_url);
+    Ok(account)
+}
+
+/// Runs the DNS-01 hook as `<hook> <set|clear> <record name> <record value>`.
+async fn run_dns01_hook(hook: &str, action: &str, name: &str, value: &str) -> Result<()> {
+    let status = tokio::process::Command::new(hook)
+        .args([action, name, value])
+        .status()
+        .await
+        .with_context(|| format!("Failed to run DNS-01 hook {hook}"))?;
+    if !status.success() {
+        bail!("DNS-01 hook {hook} {action} {name} exited with {status}");
+    }
+    Ok(())
+}
+
+/// Either a stored HTTP-01 token or a published DNS-01 record, removed once
+/// the order is settled.
+enum PendingChallenge {
+    Http01(AcmeChallenge),
+    Dns01 { name: String, value: String },
+}
+
+impl PendingChallenge {
+    async fn clean_up(&self, acme: &Acme, db: &PgPool) -> Result<()> {
+        match self {
+            Self::Http01(challenge) => challenge.delete(db).await.map_err(Into::into),
+            Self::Dns01 { name, value } => {
+                let hook = acme.dns01_hook.as_deref().context("No DNS-01 hook")?;
+                run_dns01_hook(hook, "clear", name, value).await
+            }
+        }
+    }
+}
+
+async fn prepare_challenges(
+    acme: &Acme,
+    db: &PgPool,
+    order: &mut Order,
+    pending: &mut Vec<PendingChallenge>,
+) -> Result<()> {
+    let challenge_type = if acme.dns01_hook.is_some() {
+        ChallengeType::Dns01
+    } else {
+        ChallengeType::Http01
+    };
+
+    for authorization in order.authorizations().await? {
+        match authorization.status {
+            AuthorizationStatus::Pending => {}
+            AuthorizationStatus::Valid => continue,
+            status => bail!("Unexpected authorization status {status:?}"),
+        }
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.r#type == challenge_type)
+            .with_context(|| format!("No {challenge_type:?} challenge offered"))?;
+        let key_authorization = order.key_authorization(challenge);
+
+        match (&authorization.identifier, &acme.dns01_hook) {
+            (Identifier::Dns(hostname), Some(hook)) => {
+                let name = format!("_acme-challenge.{hostname}");
+                let value = key_authorization.dns_value();
+                run_dns01_hook(hook, "set", &name, &value).await?;
+                pending.push(PendingChallenge::Dns01 { name, value });
+            }
+            (Identifier::Dns(_), None) => {
+                let challenge = AcmeChallenge::new(
+                    challenge.token.clone(),
+                    key_authorization.as_str().to_string(),
+                );
+                challenge.insert(db).await?;
+                pending.push(PendingChallenge::Http01(challenge));
+            }
+        }
+
+        order.set_challenge_ready(&challenge.url).await?;
+    }
+    Ok(())
+}
+
+async fn wait_until_ready(order: &mut Order) -> Result<()> {
+    let mut delay = Duration::from_millis(500);
+    for _ in 0..MAX_POLL_ATTEMPTS {
+        tokio::time::sleep(delay).await;
+        match order.refresh().await?.status {
+            OrderStatus::Ready => return Ok(()),
+            OrderStatus::Invalid => bail!("Order became invalid"),
+            _ => delay = (delay * 2).min(Duration::from_secs(10)),
+        }
+    }
+    Err(anyhow!(
+        "Order not ready after {MAX_POLL_ATTEMPTS} attempts"
+    ))
+}
+
+/// Orders a certificate for the custom domain and stores it alongside the
+/// domain, where the TLS resolver picks it up on its next reload.
+async fn issue(
+    acme: &Acme,
+    db: &PgPool,
+    account: &Account,
+    custom_domain: &mut CustomDomain,
+) -> Result<()> {
+    let hostname = custom_domain.hostname.clone();
+    let mut order = account
+        .new_order(&NewOrder {
+            identifiers: &[Identifier::Dns(hostname.clone())],
+        })
+        .await?;
+
+    if matches!(order.state().status, OrderStatus::Pending) {
+        let mut pending = Vec::new();
+        let result = match prepare_challenges(acme, db, &mut order, &mut pending).await {
+            Ok(()) => wait_until_ready(&mut order).await,
+            Err(e) => Err(e),
+        };
+        for challenge in &pending {
+            if let Err(e) = challenge.clean_up(acme, db).await {
+                error!("Failed to clean up ACME challenge for {hostname}: {e:#}");
+            }
+        }
+        result?;
+    }
+
+    let mut params = CertificateParams::new(vec![hostname.clone()]);
+    params.distinguished_name = DistinguishedName::new();
+    let certificate = Certificate::from_params(params)?;
+    order
+        .finalize(&certificate.serialize_request_der()?)
+        .await?;
+
+    let mut attempts = 0;
+    let certificate_pem = loop {
+        if let Some(certificate_pem) = order.certificate().await? {
+            break certificate_pem;
+        }
+        attempts += 1;
+        if attempts >= MAX_POLL_ATTEMPTS {
+            bail!("Certificate not issued after {MAX_POLL_ATTEMPTS} attempts");
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    };
+
+    custom_domain.certificate_expires_at = Some(certificate_expiry(&certificate_pem)?);
+    custom_domain.certificate_pem = Some(certificate_pem);
+    custom_domain.private_key_pem = Some(certificate.serialize_private_key_pem());
+    custom_domain.save_certificate(db).await?;
+    Ok(())
+}
+
+/// `check_interval_secs`, a zero being taken as one second rather than a
+/// busy loop.
+fn check_interval(acme: &Acme) -> Duration {
+    Duration::from_secs(acme.check_interval_secs.max(1))
+}
+
+/// Whether a domain whose last issuance failed has waited long enough, the
+/// delay doubling with each consecutive failure.
+fn is_due(acme: &Acme, custom_domain: &CustomDomain, now: DateTime<Utc>) -> bool {
+    let Some(failed_at) = custom_domain.issuance_failed_at else {
+        return true;
+    };
+    let exponent = u32::try_from(custom_domain.issuance_failures)
+        .unwrap_or_default()
+        .min(16);
+    let delay = check_interval(acme)
+        .saturating_mul(1 << exponent)
+        .min(MAX_RETRY_DELAY);
+    chrono::Duration::from_std(delay).is_ok_and(|delay| failed_at + delay <= now)
+}
+
+async fn renew_expiring(acme: &Acme, db: &PgPool) -> Result<()> {
+    let now = Utc::now();
+    let expires_before = now + chrono::Duration::days(acme.renew_before_days);
+    let custom_domains = CustomDomain::get_all_expiring_before(db, expires_before)
+        .await?
+        .into_iter()
+        .filter(|custom_domain| is_due(acme, custom_domain, now))
+        .collect::<Vec<_>>();
+    if custom_domains.is_empty() {
+        debug!("no certificate to renew");
+        return Ok(());
+    }
+
+    let account = account(acme, db).await?;
+    for mut custom_domain in custom_domains {
+        match issue(acme, db, &account, &mut custom_domain).await {
+            Ok(()) => info!("issued certificate for {}", custom_domain.hostname),
+            Err(e) => {
+                error!(
+                    "Failed to issue certificate for {}: {e:#}",
+                    custom_domain.hostname
+                );
+                custom_domain.record_issuance_failure(db).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn renew_periodically(
+    settings: Settings,
+    db: PgPool,
+    cancellation_token: CancellationToken,
+) -> Result<()> {
+    let mut interval = tokio::time::interval(check_interval(&settings.acme));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(e) = renew_expiring(&settings.acme, &db).await {
+                    error!("Failed to renew ACME certificates: {e:#}");
+                }
+            },
+            _ = cancellation_token.cancelled() => {
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use crate::connections::controller::find_connection;
+use crate::errors::{AppError, AppResponse};
+use crate::forwards::ForwardRegistry;
+use crate::proxy::controller::{forward, ForwardRequest};
+use crate::settings::Settings;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::http::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, CONTENT_LENGTH,
+};
+use actix_web::http::{Method, Uri};
+use actix_web::web::Bytes;
+use actix_web::{get, post, web, HttpResponse};
+use anyhow::{anyhow, Context};
+use sqlx::PgPool;
+use std::convert::Infallible;
+use std::future::poll_fn;
+use std::pin::pin;
+use uuid::Uuid;
+
+use super::{
+    dto,
+    models::{header_pairs, CapturedRequest, REDACTED},
+    views,
+};
+
+async fn read_body_sample(body: BoxBody, limit: usize) -> Result<(Vec<u8>, bool), AppError> {
+    let mut body = pin!(body);
+    let mut sample = Vec::new();
+    while let Some(chunk) = poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+        let chunk = chunk.map_err(|e| anyhow!(e.to_string()))?;
+        let remaining = limit - sample.len();
+        if chunk.len() > remaining {
+            sample.extend_from_slice(&chunk[..remaining]);
+            return Ok((sample, true));
+        }
+        sample.extend_from_slice(&chunk);
+    }
+    Ok((sample, false))
+}
+
+fn replay_headers(
+    captured_request: &CapturedRequest,
+    params: &dto::Replay,
+) -> Result<HeaderMap, AppError> {
+    let mut headers = HeaderMap::new();
+    // Credentials were not captured, the caller passes them again if needed.
+    for (name, value) in captured_request.request_headers.iter() {
+        if value == REDACTED {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::try_from(name.as_str()),
+            HeaderValue::try_from(value.as_str()),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    // Ask for an uncompressed response so the replay result is readable,
+    // unless the caller explicitly overrides it.
+    headers.remove(ACCEPT_ENCODING);
+    for (name, value) in &params.headers {
+        let name = HeaderName::try_from(name.as_str())
+            .map_err(|_| AppError::InvalidParams(format!("Invalid header name: {name}")))?;
+        let value = HeaderValue::try_from(value.as_str())
+            .map_err(|_| AppError::InvalidParams(format!("Invalid header value for {name}")))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+#[get("/{connection_id}/requests")]
+pub async fn index(db: web::Data<PgPool>, path: web::Path<String>) -> AppResponse {
+    let connection = find_connection(&db, path.into_inner()).await?;
+    let captured_requests = CapturedRequest::get_all_for_connection(&db, &connection.id).await?;
+    let request_views = captured_requests.iter().map(dto::View::from).collect();
+    let index_view = views::IndexView::new(&request_views);
+    let body = serde_json::to_string(&index_view)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
+#[get("/{connection_id}/requests/{request_id}")]
+pub async fn show(db: web::Data<PgPool>, path: web::Path<(String, String)>) -> AppResponse {
+    let (connection_id, request_id) = path.into_inner();
+    let connection_id =
+        Uuid::parse_str(&connection_id).context("Failed to parse connection UUID")?;
+    let request_id = Uuid::parse_str(&request_id).context("Failed to parse request UUID")?;
+    let captured_request = CapturedRequest::get(&db, &connection_id, &request_id)
+        .await
+        .map_err(|_| AppError::NotFound)?;
+    let show_view = dto::ShowView::new(dto::DetailView::from(&captured_request));
+    let body = serde_json::to_string(&show_view)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
+#[allow(clippy::future_not_send)]
+#[post("/{connection_id}/requests/{request_id}/replay")]
+pub async fn replay(
+    client: web::Data<awc::Client>,
+    registry: web::Data<ForwardRegistry>,
+    db: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    path: web::Path<(String, String)>,
+    params: Option<web::Json<dto::Replay>>,
+) -> AppResponse {
+    let (connection_id, request_id) = path.into_inner();
+    let connection = find_connection(&db, connection_id).await?;
+    let request_id = Uuid::parse_str(&request_id).context("Failed to parse request UUID")?;
+    let captured_request = CapturedRequest::get(&db, &connection.id, &request_id)
+        .await
+        .map_err(|_| AppError::NotFound)?;
+    let params = params.map(web::Json::into_inner).unwrap_or_default();
+    if captured_request.request_body_truncated && params.body.is_none() {
+        return Err(AppError::InvalidParams(
+            "The captured body was truncated, pass the body to replay".to_string(),
+        ));
+    }
+    let lease = registry
+        .acquire(&connection, None)
+        .ok_or_else(|| AppError::TunnelOffline {
+            subdomain: connection.subdomain.clone(),
+        })?;
+
+    let method = Method::from_bytes(captured_request.method.as_bytes())
+        .context("Failed to parse captured method")?;
+    let uri =
+        Uri::try_from(captured_request.path.as_str()).context("Failed to parse captured path")?;
+    let mut headers = replay_headers(&captured_request, &params)?;
+    let body = params
+        .body
+        .map_or_else(|| captured_request.request_body.clone(), String::into_bytes);
+    if !body.is_empty() || headers.contains_key(CONTENT_LENGTH) {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+
+    let forward_request = ForwardRequest {
+        method: &method,
+        uri: &uri,
+        headers: &headers,
+    };
+    let body = futures_util::stream::once(async move { Ok::<_, Infallible>(Bytes::from(body)) });
+    let resp = forward(
+        &client,
+        &db,
+        &settings,
+        &connection,
+        lease,
+        forward_request,
+        body,
+    )
+    .await?;
+
+    let status = resp.status().as_u16();
+    let resp_headers = header_pairs(resp.headers());
+    let (resp_body, resp_body_truncated) =
+        read_body_sample(resp.into_body(), settings.capture.max_body_size).await?;
+    let response_view = dto::ResponseView::new(
+        status,
+        resp_headers,
+        String::from_utf8_lossy(&resp_body).into_owned(),
+        resp_body_truncated,
+    );
+    let body = serde_json::to_string(&dto::ReplayView::new(response_view))?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
+pub fn urls(cfg: &mut web::ServiceConfig) {
+    cfg.service(index).service(show).service(replay);
+}
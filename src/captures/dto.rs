@@ -0,0 +1,77 @@
+use derive_more::Constructor;
+use serde::{Deserialize, Serialize};
+
+use super::models::{CapturedRequest, HeaderPairs};
+
+#[derive(Deserialize, Serialize)]
+pub struct View {
+    pub id: String,
+    pub method: String,
+    pub path: String,
+    pub status: Option<i32>,
+    pub duration_ms: i64,
+    pub created_at: String,
+}
+
+impl From<&CapturedRequest> for View {
+    fn from(captured: &CapturedRequest) -> Self {
+        Self {
+            id: captured.id.to_string(),
+            method: captured.method.clone(),
+            path: captured.path.clone(),
+            status: captured.status,
+            duration_ms: captured.duration_ms,
+            created_at: captured.created_at.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DetailView {
+    #[serde(flatten)]
+    pub summary: View,
+    pub request_headers: HeaderPairs,
+    pub request_body: String,
+    pub request_body_truncated: bool,
+    pub response_headers: Option<HeaderPairs>,
+}
+
+impl From<&CapturedRequest> for DetailView {
+    fn from(captured: &CapturedRequest) -> Self {
+        Self {
+            summary: View::from(captured),
+            request_headers: captured.request_headers.0.clone(),
+            request_body: String::from_utf8_lossy(&captured.request_body).into_owned(),
+            request_body_truncated: captured.request_body_truncated,
+            response_headers: captured
+                .response_headers
+                .as_ref()
+                .map(|headers| headers.0.clone()),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Constructor)]
+pub struct ShowView {
+    pub request: DetailView,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct Replay {
+    #[serde(default)]
+    pub headers: HeaderPairs,
+    pub body: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Constructor)]
+pub struct ResponseView {
+    pub status: u16,
+    pub headers: HeaderPairs,
+    pub body: String,
+    pub body_truncated: bool,
+}
+
+#[derive(Deserialize, Serialize, Constructor)]
+pub struct ReplayView {
+    pub response: ResponseView,
+}
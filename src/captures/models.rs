@@ -0,0 +1,99 @@
+use actix_web::http::header::{
+    HeaderMap, HeaderName, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE,
+};
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::types::Json;
+pub use sqlx::types::Uuid;
+use sqlx::{FromRow, PgPool, Result};
+
+pub type HeaderPairs = Vec<(String, String)>;
+
+/// Stands in for the value of credential headers, which are never stored.
+pub const REDACTED: &str = "[redacted]";
+
+const SENSITIVE_HEADERS: [HeaderName; 4] = [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE];
+
+pub fn header_pairs(headers: &HeaderMap) -> HeaderPairs {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if SENSITIVE_HEADERS.contains(name) {
+                REDACTED.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+#[derive(FromRow)]
+pub struct CapturedRequest {
+    pub id: Uuid,
+    pub connection_id: Uuid,
+    pub method: String,
+    pub path: String,
+    pub request_headers: Json<HeaderPairs>,
+    pub request_body: Vec<u8>,
+    pub request_body_truncated: bool,
+    pub status: Option<i32>,
+    pub response_headers: Option<Json<HeaderPairs>>,
+    pub duration_ms: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl CapturedRequest {
+    pub async fn insert(&self, pool: &PgPool) -> Result<()> {
+        // language=PostgreSQL
+        sqlx::query(
+            "INSERT INTO captured_requests (id, connection_id, method, path, request_headers, request_body, request_body_truncated, status, response_headers, duration_ms, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+        )
+        .bind(self.id)
+        .bind(self.connection_id)
+        .bind(&self.method)
+        .bind(&self.path)
+        .bind(&self.request_headers)
+        .bind(&self.request_body)
+        .bind(self.request_body_truncated)
+        .bind(self.status)
+        .bind(&self.response_headers)
+        .bind(self.duration_ms)
+        .bind(self.created_at)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn prune(pool: &PgPool, connection_id: &Uuid, keep: i64) -> Result<()> {
+        // language=PostgreSQL
+        sqlx::query(
+            "DELETE FROM captured_requests WHERE connection_id = $1 AND id NOT IN (SELECT id FROM captured_requests WHERE connection_id = $1 ORDER BY created_at DESC LIMIT $2)",
+        )
+        .bind(connection_id)
+        .bind(keep)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_all_for_connection(pool: &PgPool, connection_id: &Uuid) -> Result<Vec<Self>> {
+        // language=PostgreSQL
+        sqlx::query_as(
+            "SELECT * FROM captured_requests WHERE connection_id = $1 ORDER BY created_at DESC",
+        )
+        .bind(connection_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn get(pool: &PgPool, connection_id: &Uuid, uuid: &Uuid) -> Result<Self> {
+        // language=PostgreSQL
+        sqlx::query_as("SELECT * FROM captured_requests WHERE connection_id = $1 AND id = $2")
+            .bind(connection_id)
+            .bind(uuid)
+            .fetch_one(pool)
+            .await
+    }
+}
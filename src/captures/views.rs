@@ -0,0 +1,9 @@
+use derive_more::Constructor;
+use serde::Serialize;
+
+use super::dto;
+
+#[derive(Serialize, Constructor)]
+pub struct IndexView<'a> {
+    pub requests: &'a Vec<dto::View>,
+}
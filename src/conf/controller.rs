@@ -1,4 +1,4 @@
-use actix_web::{get, guard, http::header, web, HttpResponse};
+use actix_web::{get, guard, http::header, middleware, web, HttpResponse};
 
 use crate::{conf::views, errors::AppResponse, settings::Settings};
 
@@ -27,6 +27,7 @@ pub fn urls(settings: &Settings, cfg: &mut web::ServiceConfig) {
         web::scope("/conf")
             .guard(guard::Host(api_host.to_string()))
             .guard(guard::Header(header::ACCEPT.as_str(), "application/json"))
+            .wrap(middleware::Compress::default())
             .service(show),
     );
 }
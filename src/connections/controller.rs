@@ -1,26 +1,155 @@
-use crate::errors::AppResponse;
+use crate::captures;
+use crate::domains;
+use crate::errors::{AppError, AppResponse};
+use crate::proxy::cache::ResponseCache;
+use crate::routes;
 use crate::settings::Settings;
-use actix_web::{delete, get, guard, http::header, post, web, HttpResponse};
+use crate::util::parse_cidr;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{delete, get, guard, http::header, middleware, patch, post, web, HttpResponse};
 use anyhow::Context;
+use sqlx::types::Json;
 use sqlx::PgPool;
+use url::Url;
 use uuid::Uuid;
 
-use super::{dto, models::Connection, views};
+use super::{
+    dto,
+    models::{Connection, Cors, HeaderRule, HeaderRuleAction, OfflineBehaviour, RateLimit},
+    views,
+};
+
+/// Looks up the connection named in an API path.
+pub async fn find_connection(db: &PgPool, connection_id: String) -> Result<Connection, AppError> {
+    let connection_id =
+        Uuid::parse_str(&connection_id).context("Failed to parse connection UUID")?;
+    Connection::get(db, &connection_id)
+        .await
+        .map_err(|_| AppError::NotFound)
+}
+
+/// Lowercases a hostname given to the API, rejecting invalid ones and the
+/// host of the API itself.
+pub fn normalize_hostname(hostname: &str, settings: &Settings) -> Result<String, AppError> {
+    let hostname = hostname.trim().trim_end_matches('.').to_ascii_lowercase();
+    let is_valid = !hostname.is_empty()
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    if !is_valid {
+        return Err(AppError::InvalidParams(format!(
+            "Invalid hostname: {hostname}"
+        )));
+    }
+    if hostname == settings.http.url.host_str().unwrap_or_default() {
+        return Err(AppError::InvalidParams(format!(
+            "Hostname {hostname} is reserved"
+        )));
+    }
+    Ok(hostname)
+}
+
+fn normalize_cidrs(cidrs: &[String]) -> Result<Vec<String>, AppError> {
+    cidrs
+        .iter()
+        .map(|cidr| {
+            parse_cidr(cidr)
+                .map(|net| net.to_string())
+                .ok_or_else(|| AppError::InvalidParams(format!("Invalid CIDR: {cidr}")))
+        })
+        .collect()
+}
+
+fn validate_header_rules(rules: &[HeaderRule]) -> Result<(), AppError> {
+    for rule in rules {
+        HeaderName::try_from(rule.name.as_str())
+            .map_err(|_| AppError::InvalidParams(format!("Invalid header name: {}", rule.name)))?;
+        match (rule.action, &rule.value) {
+            (HeaderRuleAction::Remove, _) => {}
+            (_, Some(value)) if HeaderValue::try_from(value.as_str()).is_ok() => {}
+            _ => {
+                return Err(AppError::InvalidParams(format!(
+                    "Missing or invalid value for header rule on {}",
+                    rule.name
+                )))
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_offline_behaviour(offline_behaviour: &OfflineBehaviour) -> Result<(), AppError> {
+    if let OfflineBehaviour::Redirect { url } = offline_behaviour {
+        let is_valid = Url::parse(url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+        if !is_valid {
+            return Err(AppError::InvalidParams(format!(
+                "Invalid redirect URL: {url}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn validate_max_body_size(max_body_size: i64) -> Result<(), AppError> {
+    if max_body_size <= 0 {
+        return Err(AppError::InvalidParams(
+            "max_body_size must be positive".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_rate_limit(rate_limit: &RateLimit) -> Result<(), AppError> {
+    let buckets = [rate_limit.per_connection, rate_limit.per_ip];
+    if buckets
+        .iter()
+        .flatten()
+        .any(|bucket| bucket.requests_per_second == 0 || bucket.burst == 0)
+    {
+        return Err(AppError::InvalidParams(
+            "Rate limits need a positive requests_per_second and burst".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_cors(cors: &Cors) -> Result<(), AppError> {
+    if cors.allowed_origins.is_empty() {
+        return Err(AppError::InvalidParams(
+            "CORS needs at least one allowed origin".to_string(),
+        ));
+    }
+    if cors.allow_credentials && cors.allowed_origins.iter().any(|origin| origin == "*") {
+        return Err(AppError::InvalidParams(
+            "CORS credentials cannot be allowed for any origin".to_string(),
+        ));
+    }
+    if let Some(method) = cors
+        .allowed_methods
+        .iter()
+        .find(|method| Method::from_bytes(method.as_bytes()).is_err())
+    {
+        return Err(AppError::InvalidParams(format!(
+            "Invalid CORS method: {method}"
+        )));
+    }
+    if let Some(header) = cors
+        .allowed_headers
+        .iter()
+        .find(|header| HeaderName::try_from(header.as_str()).is_err())
+    {
+        return Err(AppError::InvalidParams(format!(
+            "Invalid CORS header: {header}"
+        )));
+    }
+    Ok(())
+}
 
 #[get("")]
 pub async fn index(db: web::Data<PgPool>) -> AppResponse {
     let connections = Connection::get_all(&db).await?;
-    let connection_views = connections
-        .into_iter()
-        .map(|x| {
-            dto::View::new(
-                x.id.to_string(),
-                x.subdomain,
-                x.proxied_port,
-                x.upstream_port,
-            )
-        })
-        .collect();
+    let connection_views = connections.iter().map(dto::View::from).collect();
     let index_view = views::IndexView::new(&connection_views);
     let body = serde_json::to_string(&index_view)?;
     Ok(HttpResponse::Ok()
@@ -30,14 +159,36 @@ pub async fn index(db: web::Data<PgPool>) -> AppResponse {
 
 #[post("")]
 pub async fn create(db: web::Data<PgPool>, params: web::Json<dto::Create>) -> AppResponse {
-    let connection = Connection::new(params.subdomain.clone(), params.proxied_port.clone());
+    let mut connection = Connection::new(params.subdomain.clone(), params.proxied_port.clone());
+    if let Some(basic_auth) = &params.basic_auth {
+        connection.set_basic_auth(basic_auth.username.clone(), &basic_auth.password)?;
+    }
+    connection.allowed_cidrs = normalize_cidrs(&params.allowed_cidrs)?;
+    connection.denied_cidrs = normalize_cidrs(&params.denied_cidrs)?;
+    connection.capture_requests = params.capture_requests;
+    validate_header_rules(&params.header_rules)?;
+    connection.header_rules = Json(params.header_rules.clone());
+    connection.load_balancing = params.load_balancing.clone().map(Json);
+    if let Some(offline_behaviour) = &params.offline_behaviour {
+        validate_offline_behaviour(offline_behaviour)?;
+    }
+    connection.offline_behaviour = params.offline_behaviour.clone().map(Json);
+    connection.cache_responses = params.cache_responses;
+    connection.compression = Json(params.compression.clone());
+    if let Some(max_body_size) = params.max_body_size {
+        validate_max_body_size(max_body_size)?;
+    }
+    connection.max_body_size = params.max_body_size;
+    if let Some(rate_limit) = &params.rate_limit {
+        validate_rate_limit(rate_limit)?;
+    }
+    connection.rate_limit = params.rate_limit.clone().map(Json);
+    if let Some(cors) = &params.cors {
+        validate_cors(cors)?;
+    }
+    connection.cors = params.cors.clone().map(Json);
     connection.insert(&db).await?;
-    let connection_view = dto::View::new(
-        connection.id.to_string(),
-        connection.subdomain.clone(),
-        connection.proxied_port.clone(),
-        connection.upstream_port.clone(),
-    );
+    let connection_view = dto::View::from(&connection);
     let create_view = dto::ShowView::new(connection_view);
     let body = serde_json::to_string(&create_view)?;
     Ok(HttpResponse::Created()
@@ -45,17 +196,86 @@ pub async fn create(db: web::Data<PgPool>, params: web::Json<dto::Create>) -> Ap
         .body(body))
 }
 
+#[patch("/{uuid}")]
+pub async fn update(
+    db: web::Data<PgPool>,
+    path: web::Path<String>,
+    params: web::Json<dto::Update>,
+) -> AppResponse {
+    let mut connection = find_connection(&db, path.into_inner()).await?;
+    let params = params.into_inner();
+    if let Some(basic_auth) = params.basic_auth {
+        match basic_auth {
+            Some(basic_auth) => {
+                connection.set_basic_auth(basic_auth.username, &basic_auth.password)?;
+            }
+            None => {
+                connection.basic_auth_username = None;
+                connection.basic_auth_password_hash = None;
+            }
+        }
+    }
+    if let Some(allowed_cidrs) = &params.allowed_cidrs {
+        connection.allowed_cidrs = normalize_cidrs(allowed_cidrs)?;
+    }
+    if let Some(denied_cidrs) = &params.denied_cidrs {
+        connection.denied_cidrs = normalize_cidrs(denied_cidrs)?;
+    }
+    if let Some(capture_requests) = params.capture_requests {
+        connection.capture_requests = capture_requests;
+    }
+    if let Some(header_rules) = params.header_rules {
+        validate_header_rules(&header_rules)?;
+        connection.header_rules = Json(header_rules);
+    }
+    if let Some(load_balancing) = params.load_balancing {
+        connection.load_balancing = load_balancing.map(Json);
+    }
+    if let Some(offline_behaviour) = params.offline_behaviour {
+        if let Some(offline_behaviour) = &offline_behaviour {
+            validate_offline_behaviour(offline_behaviour)?;
+        }
+        connection.offline_behaviour = offline_behaviour.map(Json);
+    }
+    if let Some(cache_responses) = params.cache_responses {
+        connection.cache_responses = cache_responses;
+    }
+    if let Some(compression) = params.compression {
+        connection.compression = Json(compression);
+    }
+    if let Some(max_body_size) = params.max_body_size {
+        if let Some(max_body_size) = max_body_size {
+            validate_max_body_size(max_body_size)?;
+        }
+        connection.max_body_size = max_body_size;
+    }
+    if let Some(rate_limit) = params.rate_limit {
+        if let Some(rate_limit) = &rate_limit {
+            validate_rate_limit(rate_limit)?;
+        }
+        connection.rate_limit = rate_limit.map(Json);
+    }
+    if let Some(cors) = params.cors {
+        if let Some(cors) = &cors {
+            validate_cors(cors)?;
+        }
+        connection.cors = cors.map(Json);
+    }
+    connection.save_settings(&db).await?;
+    let connection_view = dto::View::from(&connection);
+    let update_view = dto::ShowView::new(connection_view);
+    let body = serde_json::to_string(&update_view)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
 #[delete("/{uuid}")]
 pub async fn delete(db: web::Data<PgPool>, path: web::Path<String>) -> AppResponse {
     let uuid = Uuid::parse_str(&path.into_inner()).context("Failed to parse connection UUID")?;
     let connection = Connection::get(&db, &uuid).await?;
     connection.delete(&db).await?;
-    let connection_view = dto::View::new(
-        connection.id.to_string(),
-        connection.subdomain.clone(),
-        connection.proxied_port.clone(),
-        connection.upstream_port.clone(),
-    );
+    let connection_view = dto::View::from(&connection);
     let delete_view = dto::ShowView::new(connection_view);
     let body = serde_json::to_string(&delete_view)?;
     Ok(HttpResponse::Ok()
@@ -63,6 +283,24 @@ pub async fn delete(db: web::Data<PgPool>, path: web::Path<String>) -> AppRespon
         .body(body))
 }
 
+#[delete("/{uuid}/cache")]
+pub async fn purge_cache(
+    db: web::Data<PgPool>,
+    cache: web::Data<ResponseCache>,
+    path: web::Path<String>,
+) -> AppResponse {
+    let uuid = Uuid::parse_str(&path.into_inner()).context("Failed to parse connection UUID")?;
+    let connection = Connection::get(&db, &uuid)
+        .await
+        .map_err(|_| AppError::NotFound)?;
+    let purged_responses = cache.purge(&connection.id);
+    let purge_view = dto::PurgeView::new(connection.id.to_string(), purged_responses);
+    let body = serde_json::to_string(&purge_view)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
 pub fn urls(settings: &Settings, cfg: &mut web::ServiceConfig) {
     let api_host = settings
         .http
@@ -73,8 +311,14 @@ pub fn urls(settings: &Settings, cfg: &mut web::ServiceConfig) {
         web::scope("/connections")
             .guard(guard::Host(api_host.to_string()))
             .guard(guard::Header(header::ACCEPT.as_str(), "application/json"))
+            .wrap(middleware::Compress::default())
             .service(index)
             .service(create)
-            .service(delete),
+            .service(update)
+            .service(delete)
+            .service(purge_cache)
+            .configure(captures::controller::urls)
+            .configure(domains::controller::urls)
+            .configure(routes::controller::urls),
     );
 }
@@ -1,21 +1,132 @@
 use derive_more::Constructor;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use super::models::{
+    Compression, Connection, Cors, HeaderRule, LoadBalancing, OfflineBehaviour, RateLimit,
+};
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Create {
     pub subdomain: String,
     pub proxied_port: String,
+    pub basic_auth: Option<BasicAuth>,
+    #[serde(default)]
+    pub allowed_cidrs: Vec<String>,
+    #[serde(default)]
+    pub denied_cidrs: Vec<String>,
+    #[serde(default)]
+    pub capture_requests: bool,
+    #[serde(default)]
+    pub header_rules: Vec<HeaderRule>,
+    pub load_balancing: Option<LoadBalancing>,
+    pub offline_behaviour: Option<OfflineBehaviour>,
+    #[serde(default)]
+    pub cache_responses: bool,
+    #[serde(default)]
+    pub compression: Compression,
+    pub max_body_size: Option<i64>,
+    pub rate_limit: Option<RateLimit>,
+    pub cors: Option<Cors>,
 }
 
-#[derive(Deserialize, Serialize, Constructor)]
+/// Tells a field set to `null`, `Some(None)`, from a missing one, `None`.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// Settings to change on a connection. Missing fields are kept, and `null`
+/// clears the optional ones.
+#[derive(Deserialize, Debug)]
+pub struct Update {
+    #[serde(default, deserialize_with = "present")]
+    pub basic_auth: Option<Option<BasicAuth>>,
+    pub allowed_cidrs: Option<Vec<String>>,
+    pub denied_cidrs: Option<Vec<String>>,
+    pub capture_requests: Option<bool>,
+    pub header_rules: Option<Vec<HeaderRule>>,
+    #[serde(default, deserialize_with = "present")]
+    pub load_balancing: Option<Option<LoadBalancing>>,
+    #[serde(default, deserialize_with = "present")]
+    pub offline_behaviour: Option<Option<OfflineBehaviour>>,
+    pub cache_responses: Option<bool>,
+    pub compression: Option<Compression>,
+    #[serde(default, deserialize_with = "present")]
+    pub max_body_size: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub rate_limit: Option<Option<RateLimit>>,
+    #[serde(default, deserialize_with = "present")]
+    pub cors: Option<Option<Cors>>,
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct View {
     pub id: String,
     pub subdomain: String,
     pub proxied_port: String,
     pub upstream_port: Option<String>,
+    pub basic_auth_username: Option<String>,
+    pub allowed_cidrs: Vec<String>,
+    pub denied_cidrs: Vec<String>,
+    pub capture_requests: bool,
+    pub header_rules: Vec<HeaderRule>,
+    pub load_balancing: Option<LoadBalancing>,
+    pub offline_behaviour: Option<OfflineBehaviour>,
+    pub cache_responses: bool,
+    pub compression: Compression,
+    pub max_body_size: Option<i64>,
+    pub rate_limit: Option<RateLimit>,
+    pub cors: Option<Cors>,
+}
+
+impl From<&Connection> for View {
+    fn from(connection: &Connection) -> Self {
+        Self {
+            id: connection.id.to_string(),
+            subdomain: connection.subdomain.clone(),
+            proxied_port: connection.proxied_port.clone(),
+            upstream_port: connection.upstream_port.clone(),
+            basic_auth_username: connection.basic_auth_username.clone(),
+            allowed_cidrs: connection.allowed_cidrs.clone(),
+            denied_cidrs: connection.denied_cidrs.clone(),
+            capture_requests: connection.capture_requests,
+            header_rules: connection.header_rules.0.clone(),
+            load_balancing: connection
+                .load_balancing
+                .as_ref()
+                .map(|load_balancing| load_balancing.0.clone()),
+            offline_behaviour: connection
+                .offline_behaviour
+                .as_ref()
+                .map(|offline_behaviour| offline_behaviour.0.clone()),
+            cache_responses: connection.cache_responses,
+            compression: connection.compression.0.clone(),
+            max_body_size: connection.max_body_size,
+            rate_limit: connection
+                .rate_limit
+                .as_ref()
+                .map(|rate_limit| rate_limit.0.clone()),
+            cors: connection.cors.as_ref().map(|cors| cors.0.clone()),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Constructor)]
 pub struct ShowView {
     pub connection: View,
 }
+
+#[derive(Deserialize, Serialize, Constructor)]
+pub struct PurgeView {
+    pub connection_id: String,
+    pub purged_responses: usize,
+}
@@ -1,5 +1,115 @@
+use anyhow::Context;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
 pub use sqlx::types::Uuid;
 use sqlx::{FromRow, PgPool, Result};
+use subtle::ConstantTimeEq;
+
+use crate::settings::Settings;
+use crate::util::extract_subdomain;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderRuleTarget {
+    Request,
+    Response,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderRuleAction {
+    Set,
+    Append,
+    Remove,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct HeaderRule {
+    pub target: HeaderRuleTarget,
+    pub action: HeaderRuleAction,
+    pub name: String,
+    pub value: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BalancingStrategy {
+    RoundRobin,
+    LeastConnections,
+    Sticky,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct LoadBalancing {
+    pub strategy: BalancingStrategy,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionMode {
+    #[default]
+    Passthrough,
+    Compress,
+    Decompress,
+}
+
+/// How the proxy treats response encodings. An empty `content_types` list
+/// falls back to common text formats.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Compression {
+    pub mode: CompressionMode,
+    #[serde(default)]
+    pub content_types: Vec<String>,
+}
+
+/// A token bucket refilled at `requests_per_second` and holding at most
+/// `burst` requests.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenBucket {
+    pub requests_per_second: u32,
+    pub burst: u32,
+}
+
+/// Buckets shared by every visitor of the connection and kept for each
+/// visitor address. Either may be left out.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct RateLimit {
+    pub per_connection: Option<TokenBucket>,
+    pub per_ip: Option<TokenBucket>,
+}
+
+/// Cross-origin policy applied by the proxy on behalf of the tunnelled app.
+/// Empty method and header lists allow whatever the preflight asks for.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Cors {
+    pub allowed_origins: Vec<String>,
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    pub max_age_secs: Option<u64>,
+}
+
+/// What visitors get while no client is attached to the connection.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OfflineBehaviour {
+    Page {
+        html: String,
+    },
+    Redirect {
+        url: String,
+    },
+    Maintenance {
+        message: Option<String>,
+        retry_after_secs: u64,
+    },
+}
 
 #[derive(FromRow)]
 pub struct Connection {
@@ -8,6 +118,19 @@ pub struct Connection {
     pub proxied_port: String,
     pub proxy_port: Option<String>,
     pub upstream_port: Option<String>,
+    pub basic_auth_username: Option<String>,
+    pub basic_auth_password_hash: Option<String>,
+    pub allowed_cidrs: Vec<String>,
+    pub denied_cidrs: Vec<String>,
+    pub capture_requests: bool,
+    pub header_rules: Json<Vec<HeaderRule>>,
+    pub load_balancing: Option<Json<LoadBalancing>>,
+    pub offline_behaviour: Option<Json<OfflineBehaviour>>,
+    pub cache_responses: bool,
+    pub compression: Json<Compression>,
+    pub max_body_size: Option<i64>,
+    pub rate_limit: Option<Json<RateLimit>>,
+    pub cors: Option<Json<Cors>>,
 }
 
 impl Connection {
@@ -18,17 +141,79 @@ impl Connection {
             proxied_port,
             proxy_port: None,
             upstream_port: None,
+            basic_auth_username: None,
+            basic_auth_password_hash: None,
+            allowed_cidrs: Vec::new(),
+            denied_cidrs: Vec::new(),
+            capture_requests: false,
+            header_rules: Json(Vec::new()),
+            load_balancing: None,
+            offline_behaviour: None,
+            cache_responses: false,
+            compression: Json(Compression::default()),
+            max_body_size: None,
+            rate_limit: None,
+            cors: None,
         }
     }
 
+    pub fn set_basic_auth(&mut self, username: String, password: &str) -> anyhow::Result<()> {
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .context("Failed to hash basic auth password")?;
+
+        self.basic_auth_username = Some(username);
+        self.basic_auth_password_hash = Some(password_hash.to_string());
+        Ok(())
+    }
+
+    pub const fn requires_basic_auth(&self) -> bool {
+        self.basic_auth_password_hash.is_some()
+    }
+
+    /// Checks credentials against the stored username and Argon2 hash. This is
+    /// CPU heavy, so callers run it off the async workers.
+    pub fn verify_basic_auth(
+        expected_username: &str,
+        password_hash: &str,
+        username: &str,
+        password: &str,
+    ) -> bool {
+        let Ok(password_hash) = PasswordHash::new(password_hash) else {
+            return false;
+        };
+
+        let username_matches = username.as_bytes().ct_eq(expected_username.as_bytes());
+        let password_matches = Argon2::default()
+            .verify_password(password.as_bytes(), &password_hash)
+            .is_ok();
+        username_matches.unwrap_u8() == 1 && password_matches
+    }
+
     pub async fn insert(&self, pool: &PgPool) -> Result<()> {
         // language=PostgreSQL
-        sqlx::query("INSERT INTO connections (id, subdomain, proxied_port) VALUES ($1, $2, $3)")
-            .bind(self.id)
-            .bind(&self.subdomain)
-            .bind(&self.proxied_port)
-            .execute(pool)
-            .await?;
+        sqlx::query(
+            "INSERT INTO connections (id, subdomain, proxied_port, basic_auth_username, basic_auth_password_hash, allowed_cidrs, denied_cidrs, capture_requests, header_rules, load_balancing, offline_behaviour, cache_responses, compression, max_body_size, rate_limit, cors) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)",
+        )
+        .bind(self.id)
+        .bind(&self.subdomain)
+        .bind(&self.proxied_port)
+        .bind(&self.basic_auth_username)
+        .bind(&self.basic_auth_password_hash)
+        .bind(&self.allowed_cidrs)
+        .bind(&self.denied_cidrs)
+        .bind(self.capture_requests)
+        .bind(&self.header_rules)
+        .bind(&self.load_balancing)
+        .bind(&self.offline_behaviour)
+        .bind(self.cache_responses)
+        .bind(&self.compression)
+        .bind(self.max_body_size)
+        .bind(&self.rate_limit)
+        .bind(&self.cors)
+        .execute(pool)
+        .await?;
 
         Ok(())
     }
@@ -46,6 +231,32 @@ impl Connection {
         Ok(())
     }
 
+    /// Saves the settings set through the API, leaving the tunnel state alone.
+    pub async fn save_settings(&self, pool: &PgPool) -> Result<()> {
+        // language=PostgreSQL
+        sqlx::query(
+            "UPDATE connections SET (basic_auth_username, basic_auth_password_hash, allowed_cidrs, denied_cidrs, capture_requests, header_rules, load_balancing, offline_behaviour, cache_responses, compression, max_body_size, rate_limit, cors) = ($2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14) WHERE id = $1",
+        )
+        .bind(self.id)
+        .bind(&self.basic_auth_username)
+        .bind(&self.basic_auth_password_hash)
+        .bind(&self.allowed_cidrs)
+        .bind(&self.denied_cidrs)
+        .bind(self.capture_requests)
+        .bind(&self.header_rules)
+        .bind(&self.load_balancing)
+        .bind(&self.offline_behaviour)
+        .bind(self.cache_responses)
+        .bind(&self.compression)
+        .bind(self.max_body_size)
+        .bind(&self.rate_limit)
+        .bind(&self.cors)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn get_all(pool: &PgPool) -> Result<Vec<Self>> {
         // language=PostgreSQL
         sqlx::query_as("SELECT * FROM connections")
@@ -69,6 +280,26 @@ impl Connection {
             .await
     }
 
+    /// Looks the host up among custom domains first, then falls back to the
+    /// subdomain of `vhost_suffix`.
+    pub async fn get_by_host(pool: &PgPool, host: &str, settings: &Settings) -> Result<Self> {
+        let hostname = host.split(':').next().unwrap_or(host).to_ascii_lowercase();
+        // language=PostgreSQL
+        let custom_domain_connection = sqlx::query_as(
+            "SELECT connections.* FROM connections INNER JOIN custom_domains ON custom_domains.connection_id = connections.id WHERE custom_domains.hostname = $1",
+        )
+        .bind(&hostname)
+        .fetch_optional(pool)
+        .await?;
+        if let Some(connection) = custom_domain_connection {
+            return Ok(connection);
+        }
+
+        let subdomain =
+            extract_subdomain(&hostname, settings).map_err(|_| sqlx::Error::RowNotFound)?;
+        Self::get_by_subdomain(pool, &subdomain).await
+    }
+
     pub async fn delete(&self, pool: &PgPool) -> Result<()> {
         // language=PostgreSQL
         sqlx::query("DELETE FROM connections WHERE id = $1")
@@ -0,0 +1,101 @@
+use crate::connections::controller::{find_connection, normalize_hostname};
+use crate::errors::{AppError, AppResponse};
+use crate::settings::Settings;
+use crate::tls;
+use actix_web::{delete, get, post, put, web, HttpResponse};
+use sqlx::PgPool;
+
+use super::{dto, models::CustomDomain, views};
+
+#[get("/{connection_id}/domains")]
+pub async fn index(db: web::Data<PgPool>, path: web::Path<String>) -> AppResponse {
+    let connection = find_connection(&db, path.into_inner()).await?;
+    let custom_domains = CustomDomain::get_all_for_connection(&db, &connection.id).await?;
+    let domain_views = custom_domains.iter().map(dto::View::from).collect();
+    let index_view = views::IndexView::new(&domain_views);
+    let body = serde_json::to_string(&index_view)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
+#[post("/{connection_id}/domains")]
+pub async fn create(
+    db: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    path: web::Path<String>,
+    params: web::Json<dto::Create>,
+) -> AppResponse {
+    let connection = find_connection(&db, path.into_inner()).await?;
+    let hostname = normalize_hostname(&params.hostname, &settings)?;
+    // Subdomains of the suffix already belong to their connections.
+    if hostname.ends_with(&*settings.http.vhost_suffix) {
+        return Err(AppError::InvalidParams(format!(
+            "Hostname {hostname} is reserved"
+        )));
+    }
+    let custom_domain = CustomDomain::new(connection.id, hostname);
+    custom_domain.insert(&db).await?;
+    let show_view = dto::ShowView::new(dto::View::from(&custom_domain));
+    let body = serde_json::to_string(&show_view)?;
+    Ok(HttpResponse::Created()
+        .content_type("application/json")
+        .body(body))
+}
+
+#[delete("/{connection_id}/domains/{hostname}")]
+pub async fn delete(db: web::Data<PgPool>, path: web::Path<(String, String)>) -> AppResponse {
+    let (connection_id, hostname) = path.into_inner();
+    let connection = find_connection(&db, connection_id).await?;
+    let custom_domain =
+        CustomDomain::get_by_hostname(&db, &connection.id, &hostname.to_ascii_lowercase())
+            .await
+            .map_err(|_| AppError::NotFound)?;
+    custom_domain.delete(&db).await?;
+    let show_view = dto::ShowView::new(dto::View::from(&custom_domain));
+    let body = serde_json::to_string(&show_view)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
+/// Stores a certificate for the domain, which the TLS listener serves from
+/// its next reload.
+#[put("/{connection_id}/domains/{hostname}/certificate")]
+pub async fn update_certificate(
+    db: web::Data<PgPool>,
+    path: web::Path<(String, String)>,
+    params: web::Json<dto::Certificate>,
+) -> AppResponse {
+    let (connection_id, hostname) = path.into_inner();
+    let connection = find_connection(&db, connection_id).await?;
+    let mut custom_domain =
+        CustomDomain::get_by_hostname(&db, &connection.id, &hostname.to_ascii_lowercase())
+            .await
+            .map_err(|_| AppError::NotFound)?;
+    let params = params.into_inner();
+    tls::certified_key(
+        params.certificate_pem.as_bytes(),
+        params.private_key_pem.as_bytes(),
+    )
+    .map_err(|e| AppError::InvalidParams(format!("Invalid certificate: {e:#}")))?;
+    let expires_at = tls::certificate_expiry(&params.certificate_pem)
+        .map_err(|e| AppError::InvalidParams(format!("Invalid certificate: {e:#}")))?;
+
+    custom_domain.certificate_pem = Some(params.certificate_pem);
+    custom_domain.private_key_pem = Some(params.private_key_pem);
+    custom_domain.certificate_expires_at = Some(expires_at);
+    custom_domain.save_certificate(&db).await?;
+    let show_view = dto::ShowView::new(dto::View::from(&custom_domain));
+    let body = serde_json::to_string(&show_view)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
+pub fn urls(cfg: &mut web::ServiceConfig) {
+    cfg.service(index)
+        .service(create)
+        .service(delete)
+        .service(update_certificate);
+}
@@ -0,0 +1,45 @@
+use derive_more::Constructor;
+use serde::{Deserialize, Serialize};
+
+use super::models::CustomDomain;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Create {
+    pub hostname: String,
+}
+
+/// A certificate obtained outside of ACME. With ACME enabled, it is renewed
+/// like issued ones once it nears expiry.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Certificate {
+    pub certificate_pem: String,
+    pub private_key_pem: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct View {
+    pub id: String,
+    pub connection_id: String,
+    pub hostname: String,
+    pub has_certificate: bool,
+    pub certificate_expires_at: Option<String>,
+}
+
+impl From<&CustomDomain> for View {
+    fn from(custom_domain: &CustomDomain) -> Self {
+        Self {
+            id: custom_domain.id.to_string(),
+            connection_id: custom_domain.connection_id.to_string(),
+            hostname: custom_domain.hostname.clone(),
+            has_certificate: custom_domain.certificate_pem.is_some(),
+            certificate_expires_at: custom_domain
+                .certificate_expires_at
+                .map(|expires_at| expires_at.to_rfc3339()),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Constructor)]
+pub struct ShowView {
+    pub domain: View,
+}
@@ -0,0 +1,123 @@
+use sqlx::types::chrono::{DateTime, Utc};
+pub use sqlx::types::Uuid;
+use sqlx::{FromRow, PgPool, Result};
+
+#[derive(FromRow)]
+pub struct CustomDomain {
+    pub id: Uuid,
+    pub connection_id: Uuid,
+    pub hostname: String,
+    pub certificate_pem: Option<String>,
+    pub private_key_pem: Option<String>,
+    pub certificate_expires_at: Option<DateTime<Utc>>,
+    pub issuance_failures: i32,
+    pub issuance_failed_at: Option<DateTime<Utc>>,
+}
+
+impl CustomDomain {
+    pub fn new(connection_id: Uuid, hostname: String) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            connection_id,
+            hostname,
+            certificate_pem: None,
+            private_key_pem: None,
+            certificate_expires_at: None,
+            issuance_failures: 0,
+            issuance_failed_at: None,
+        }
+    }
+
+    pub async fn insert(&self, pool: &PgPool) -> Result<()> {
+        // language=PostgreSQL
+        sqlx::query("INSERT INTO custom_domains (id, connection_id, hostname) VALUES ($1, $2, $3)")
+            .bind(self.id)
+            .bind(self.connection_id)
+            .bind(&self.hostname)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Stores a new certificate, which also clears past issuance failures.
+    pub async fn save_certificate(&self, pool: &PgPool) -> Result<()> {
+        // language=PostgreSQL
+        sqlx::query("UPDATE custom_domains SET (certificate_pem, private_key_pem, certificate_expires_at, issuance_failures, issuance_failed_at) = ($2, $3, $4, 0, NULL) WHERE id = $1")
+            .bind(self.id)
+            .bind(&self.certificate_pem)
+            .bind(&self.private_key_pem)
+            .bind(self.certificate_expires_at)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn record_issuance_failure(&mut self, pool: &PgPool) -> Result<()> {
+        self.issuance_failures += 1;
+        self.issuance_failed_at = Some(Utc::now());
+        // language=PostgreSQL
+        sqlx::query("UPDATE custom_domains SET (issuance_failures, issuance_failed_at) = ($2, $3) WHERE id = $1")
+            .bind(self.id)
+            .bind(self.issuance_failures)
+            .bind(self.issuance_failed_at)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_all_expiring_before(
+        pool: &PgPool,
+        expires_before: DateTime<Utc>,
+    ) -> Result<Vec<Self>> {
+        // language=PostgreSQL
+        sqlx::query_as(
+            "SELECT * FROM custom_domains WHERE certificate_expires_at IS NULL OR certificate_expires_at < $1",
+        )
+        .bind(expires_before)
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn get_all_for_connection(pool: &PgPool, connection_id: &Uuid) -> Result<Vec<Self>> {
+        // language=PostgreSQL
+        sqlx::query_as("SELECT * FROM custom_domains WHERE connection_id = $1 ORDER BY hostname")
+            .bind(connection_id)
+            .fetch_all(pool)
+            .await
+    }
+
+    pub async fn get_all_with_certificates(pool: &PgPool) -> Result<Vec<Self>> {
+        // language=PostgreSQL
+        sqlx::query_as(
+            "SELECT * FROM custom_domains WHERE certificate_pem IS NOT NULL AND private_key_pem IS NOT NULL",
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn get_by_hostname(
+        pool: &PgPool,
+        connection_id: &Uuid,
+        hostname: &str,
+    ) -> Result<Self> {
+        // language=PostgreSQL
+        sqlx::query_as("SELECT * FROM custom_domains WHERE connection_id = $1 AND hostname = $2")
+            .bind(connection_id)
+            .bind(hostname)
+            .fetch_one(pool)
+            .await
+    }
+
+    pub async fn delete(&self, pool: &PgPool) -> Result<()> {
+        // language=PostgreSQL
+        sqlx::query("DELETE FROM custom_domains WHERE id = $1")
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -1,12 +1,18 @@
+use crate::proxy::rate_limit::Quota;
+use crate::request_id;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header;
 use actix_web::http::StatusCode;
+use actix_web::middleware::ErrorHandlerResponse;
 use actix_web::HttpResponse;
 use actix_web::ResponseError;
 use askama::Template;
 use derive_more::Constructor;
+use serde::Serialize;
 use std::fmt::Debug;
 use thiserror::Error;
 use tokio::task::JoinError;
-use tracing::error;
+use tracing::{error, warn};
 
 #[derive(Template, Constructor)]
 #[template(path = "errors.html")]
@@ -14,28 +20,16 @@ pub struct ErrorView<'a> {
     pub title: &'a str,
     pub code: i32,
     pub msg: &'a str,
+    pub request_id: Option<&'a str>,
 }
 
-fn internal_server_error(e: &dyn std::error::Error) -> HttpResponse {
-    error!("Internal server error: {:#?}", e);
-    let mut res = HttpResponse::InternalServerError();
-    res.content_type("text/html");
-    let template = ErrorView::new("Internal Server Error", 500, "Internal Server Error.");
-    let Ok(body) = template.render() else {
-        return res.finish();
-    };
-    res.body(body)
-}
-
-fn unprocessable_entity(msg: &str) -> HttpResponse {
-    error!("Unprocessable_entity: {:#?}", msg);
-    let mut res = HttpResponse::UnprocessableEntity();
-    res.content_type("text/html");
-    let template = ErrorView::new("Unprocessable Entity", 422, msg);
-    let Ok(body) = template.render() else {
-        return res.finish();
-    };
-    res.body(body)
+#[derive(Serialize)]
+struct ErrorJson<'a> {
+    code: u16,
+    error: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
 }
 
 #[derive(Error, Debug)]
@@ -54,36 +48,204 @@ pub enum AppError {
     Russh(#[from] russh_keys::Error),
     #[error("not found")]
     NotFound,
+    #[error("no tunnel for {subdomain}")]
+    TunnelNotFound { subdomain: String },
+    #[error("tunnel {subdomain} is offline")]
+    TunnelOffline { subdomain: String },
+    #[error("upstream of tunnel {subdomain} refused the connection")]
+    UpstreamRefused { subdomain: String },
+    #[error("upstream of tunnel {subdomain} sent an invalid response")]
+    UpstreamInvalid { subdomain: String },
+    #[error("upstream of tunnel {subdomain} timed out")]
+    UpstreamTimeout { subdomain: String },
+    #[error("tunnel {subdomain} did not reconnect in time")]
+    ReconnectTimeout { subdomain: String },
+    #[error("request body over the limit of {limit} bytes")]
+    PayloadTooLarge { limit: usize },
+    #[error("rate limited")]
+    RateLimited { quota: Quota },
+    #[error("unauthorized for realm {realm}")]
+    Unauthorized { realm: String },
+    #[error("forbidden")]
+    Forbidden,
+    #[error("invalid parameters: {0}")]
+    InvalidParams(String),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+impl AppError {
+    /// Title and message shown to visitors, as an HTML page or as JSON.
+    pub fn describe(&self) -> (String, String) {
+        let (title, msg) = match self {
+            Self::NotFound => (
+                "Error 404",
+                "The page you visited was not found.".to_string(),
+            ),
+            Self::TunnelNotFound { subdomain } => (
+                "Error 404",
+                format!("There is no tunnel named {subdomain}."),
+            ),
+            Self::TunnelOffline { subdomain } => (
+                "Tunnel offline",
+                format!("The tunnel {subdomain} has no client attached right now."),
+            ),
+            Self::UpstreamRefused { subdomain } => (
+                "Upstream refused",
+                format!("The tunnel {subdomain} is up but nothing answered on its local port."),
+            ),
+            Self::UpstreamInvalid { subdomain } => (
+                "Invalid upstream response",
+                format!("The tunnel {subdomain} answered with an invalid response."),
+            ),
+            Self::UpstreamTimeout { subdomain } => (
+                "Upstream timeout",
+                format!("The tunnel {subdomain} did not answer in time."),
+            ),
+            Self::ReconnectTimeout { subdomain } => (
+                "Tunnel reconnecting",
+                format!("The tunnel {subdomain} lost its client and it did not come back in time."),
+            ),
+            Self::PayloadTooLarge { limit } => (
+                "Error 413",
+                format!("Request bodies sent to this tunnel are limited to {limit} bytes."),
+            ),
+            Self::RateLimited { quota } => (
+                "Error 429",
+                format!(
+                    "Too many requests were sent to this tunnel. Try again in {} seconds.",
+                    quota.retry_after_secs
+                ),
+            ),
+            Self::Unauthorized { .. } => (
+                "Error 401",
+                "This tunnel requires authentication.".to_string(),
+            ),
+            Self::Forbidden => (
+                "Error 403",
+                "Access to this tunnel is not allowed from your address.".to_string(),
+            ),
+            Self::InvalidParams(msg) => ("Unprocessable Entity", msg.clone()),
+            Self::Database(reason) => (
+                "Unprocessable Entity",
+                reason
+                    .as_database_error()
+                    .map_or("Database error", |e| e.message())
+                    .to_string(),
+            ),
+            _ => (
+                "Internal Server Error",
+                "Internal Server Error.".to_string(),
+            ),
+        };
+        (title.to_string(), msg)
+    }
+}
+
 impl ResponseError for AppError {
     fn status_code(&self) -> StatusCode {
-        StatusCode::INTERNAL_SERVER_ERROR
+        match self {
+            Self::NotFound | Self::TunnelNotFound { .. } => StatusCode::NOT_FOUND,
+            Self::TunnelOffline { .. }
+            | Self::UpstreamRefused { .. }
+            | Self::UpstreamInvalid { .. } => StatusCode::BAD_GATEWAY,
+            Self::UpstreamTimeout { .. } | Self::ReconnectTimeout { .. } => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            Self::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            Self::InvalidParams(_) | Self::Database(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
 
     #[tracing::instrument]
     fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let (title, msg) = self.describe();
         match self {
-            Self::NotFound => {
-                let mut res = HttpResponse::NotFound();
-                res.content_type("text/html");
-                let template =
-                    ErrorView::new("Error 404", 404, "The page you visited was not found.");
-                let Ok(body) = template.render() else {
-                    return res.finish();
-                };
-                res.body(body)
+            Self::InvalidParams(_) | Self::Database(_) => {
+                error!("Unprocessable_entity: {:#?}", msg);
             }
-            Self::Database(reason) => unprocessable_entity(
-                reason
-                    .as_database_error()
-                    .map_or("Database error", |e| e.message()),
-            ),
-            e => internal_server_error(e),
+            Self::TunnelOffline { .. }
+            | Self::UpstreamRefused { .. }
+            | Self::UpstreamInvalid { .. }
+            | Self::UpstreamTimeout { .. }
+            | Self::ReconnectTimeout { .. } => warn!("{self}"),
+            _ if status.is_server_error() => error!("Internal server error: {:#?}", self),
+            _ => {}
+        }
+
+        let mut res = HttpResponse::build(status);
+        res.content_type("text/html");
+        if let Self::Unauthorized { realm } = self {
+            res.insert_header((
+                header::WWW_AUTHENTICATE,
+                format!("Basic realm=\"{realm}\", charset=\"UTF-8\""),
+            ));
+        }
+        if let Self::RateLimited { quota } = self {
+            res.insert_header((header::RETRY_AFTER, quota.retry_after_secs));
+        }
+        let template = ErrorView::new(&title, i32::from(status.as_u16()), &msg, None);
+        let mut resp = match template.render() {
+            Ok(body) => res.body(body),
+            Err(_) => res.finish(),
+        };
+        if let Self::RateLimited { quota } = self {
+            quota.insert_headers(resp.headers_mut());
+        }
+        resp
+    }
+}
+
+/// Renders the page of an [`AppError`] again now that the request is known,
+/// with the request id visitors can quote, and as JSON for clients that ask
+/// for it. Other error responses, such as those of tunnelled applications,
+/// are left untouched.
+pub fn render_errors<B>(res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    let app_error = res
+        .response()
+        .error()
+        .and_then(actix_web::Error::as_error::<AppError>);
+    let Some(app_error) = app_error else {
+        return Ok(ErrorHandlerResponse::Response(res.map_into_left_body()));
+    };
+    let wants_json = res
+        .request()
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    let request_id = request_id::get(res.request().headers());
+
+    let (title, msg) = app_error.describe();
+    let status = res.status();
+    let (content_type, body) = if wants_json {
+        let body = serde_json::to_string(&ErrorJson {
+            code: status.as_u16(),
+            error: &title,
+            message: &msg,
+            request_id,
+        })?;
+        ("application/json", body)
+    } else {
+        let template = ErrorView::new(&title, i32::from(status.as_u16()), &msg, request_id);
+        ("text/html", template.render().map_err(AppError::from)?)
+    };
+    let mut error_res = HttpResponse::build(status);
+    for (name, value) in res.headers() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            error_res.append_header((name.clone(), value.clone()));
         }
     }
+    let error_res = error_res.content_type(content_type).body(body);
+    let (req, _) = res.into_parts();
+    Ok(ErrorHandlerResponse::Response(
+        ServiceResponse::new(req, error_res).map_into_right_body(),
+    ))
 }
 
 pub type AppResponse<T = HttpResponse> = Result<T, AppError>;
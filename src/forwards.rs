@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::connections::models::{BalancingStrategy, Connection};
+
+/// How long a forward whose channel failed to open is skipped before a
+/// request tries it again.
+const UNAVAILABLE_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// A listener bound on behalf of one SSH client for a connection.
+pub struct Forward {
+    pub id: u64,
+    pub port: u16,
+    /// When a channel last failed to open, until one opens again.
+    unavailable_since: Mutex<Option<Instant>>,
+    active_requests: AtomicUsize,
+}
+
+impl Forward {
+    /// Forwards marked unavailable are let back in after a cooldown, so the
+    /// next request probes them again.
+    pub fn is_available(&self) -> bool {
+        self.unavailable_since
+            .lock()
+            .map_or(true, |unavailable_since| {
+                !unavailable_since.is_some_and(|since| since.elapsed() < UNAVAILABLE_COOLDOWN)
+            })
+    }
+
+    pub fn mark_available(&self) {
+        if let Ok(mut unavailable_since) = self.unavailable_since.lock() {
+            *unavailable_since = None;
+        }
+    }
+
+    pub fn mark_unavailable(&self) {
+        if let Ok(mut unavailable_since) = self.unavailable_since.lock() {
+            *unavailable_since = Some(Instant::now());
+        }
+    }
+}
+
+/// Counts a request against its forward until dropped.
+pub struct Lease(Arc<Forward>);
+
+impl Lease {
+    fn new(forward: Arc<Forward>) -> Self {
+        forward.active_requests.fetch_add(1, Ordering::Relaxed);
+        Self(forward)
+    }
+
+    pub fn forward(&self) -> &Forward {
+        &self.0
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.active_requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct Pool {
+    forwards: Vec<Arc<Forward>>,
+    next: usize,
+}
+
+/// Outcome of waiting for a client to come back to a connection.
+pub enum Reconnect {
+    Lease(Lease),
+    TimedOut,
+    Offline,
+}
+
+/// Live forwards of every connection, shared by the SSH server which opens
+/// them and the proxy which spreads requests across them.
+pub struct ForwardRegistry {
+    pools: Mutex<HashMap<Uuid, Pool>>,
+    /// When connections lost their last forward, until one is registered again
+    /// or `reconnect_grace` is over.
+    disconnected: Mutex<HashMap<Uuid, Instant>>,
+    reconnect_grace: Duration,
+    registered: Notify,
+    next_id: AtomicU64,
+}
+
+impl ForwardRegistry {
+    pub fn new(reconnect_grace: Duration) -> Self {
+        Self {
+            pools: Mutex::default(),
+            disconnected: Mutex::default(),
+            reconnect_grace,
+            registered: Notify::new(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn register(&self, connection_id: Uuid, port: u16) -> Arc<Forward> {
+        let forward = Arc::new(Forward {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            port,
+            unavailable_since: Mutex::new(None),
+            active_requests: AtomicUsize::new(0),
+        });
+        if let Ok(mut pools) = self.pools.lock() {
+            pools
+                .entry(connection_id)
+                .or_default()
+                .forwards
+                .push(forward.clone());
+        }
+        if let Ok(mut disconnected) = self.disconnected.lock() {
+            disconnected.remove(&connection_id);
+        }
+        self.registered.notify_waiters();
+        forward
+    }
+
+    pub fn unregister(&self, connection_id: &Uuid, forward_id: u64) {
+        let Ok(mut pools) = self.pools.lock() else {
+            return;
+        };
+        if let Some(pool) = pools.get_mut(connection_id) {
+            pool.forwards.retain(|forward| forward.id != forward_id);
+            if pool.forwards.is_empty() {
+                pools.remove(connection_id);
+                if let Ok(mut disconnected) = self.disconnected.lock() {
+                    let reconnect_grace = self.reconnect_grace;
+                    disconnected
+                        .retain(|_, disconnected_at| disconnected_at.elapsed() < reconnect_grace);
+                    disconnected.insert(*connection_id, Instant::now());
+                }
+            }
+        }
+    }
+
+    pub fn is_online(&self, connection_id: &Uuid) -> bool {
+        self.pools
+            .lock()
+            .is_ok_and(|pools| pools.contains_key(connection_id))
+    }
+
+    /// Picks the forward serving the next request of the connection. Without
+    /// load balancing the most recent client wins. Unavailable forwards are
+    /// skipped unless none is left, until their cooldown is over. `sticky_id`
+    /// is the forward remembered by the visitor's cookie.
+    pub fn acquire(&self, connection: &Connection, sticky_id: Option<u64>) -> Option<Lease> {
+        let mut pools = self.pools.lock().ok()?;
+        let Pool { forwards, next } = pools.get_mut(&connection.id)?;
+        let Some(load_balancing) = &connection.load_balancing else {
+            return forwards.last().cloned().map(Lease::new);
+        };
+
+        let available = forwards
+            .iter()
+            .filter(|forward| forward.is_available())
+            .collect::<Vec<_>>();
+        let candidates = if available.is_empty() {
+            forwards.iter().collect::<Vec<_>>()
+        } else {
+            available
+        };
+        if candidates.is_empty() {
+            return None;
+        }
+        let sticky = sticky_id.and_then(|sticky_id| {
+            candidates
+                .iter()
+                .find(|forward| forward.id == sticky_id)
+                .copied()
+        });
+
+        let forward = match (load_balancing.strategy, sticky) {
+            (BalancingStrategy::Sticky, Some(forward)) => forward,
+            (BalancingStrategy::LeastConnections, _) => candidates
+                .iter()
+                .min_by_key(|forward| forward.active_requests.load(Ordering::Relaxed))
+                .copied()?,
+            (BalancingStrategy::RoundRobin | BalancingStrategy::Sticky, _) => {
+                *next = next.wrapping_add(1);
+                candidates.get(*next % candidates.len()).copied()?
+            }
+        };
+        Some(Lease::new(forward.clone()))
+    }
+
+    /// Waits for a client to come back to a connection whose last one left
+    /// less than the reconnect grace period ago. Connections that were not
+    /// online recently are reported offline right away.
+    pub async fn wait_for_reconnect(
+        &self,
+        connection: &Connection,
+        sticky_id: Option<u64>,
+    ) -> Reconnect {
+        let disconnected_at = self.disconnected.lock().ok().and_then(|mut disconnected| {
+            let disconnected_at = disconnected.get(&connection.id).copied()?;
+            if disconnected_at.elapsed() < self.reconnect_grace {
+                return Some(disconnected_at);
+            }
+            disconnected.remove(&connection.id);
+            None
+        });
+        let Some(disconnected_at) = disconnected_at else {
+            return Reconnect::Offline;
+        };
+
+        let deadline = tokio::time::Instant::from_std(disconnected_at + self.reconnect_grace);
+        loop {
+            let registered = self.registered.notified();
+            tokio::pin!(registered);
+            // Registered before looking, so a client coming back in between
+            // still wakes us up.
+            registered.as_mut().enable();
+            if let Some(lease) = self.acquire(connection, sticky_id) {
+                return Reconnect::Lease(lease);
+            }
+            if tokio::time::timeout_at(deadline, registered).await.is_err() {
+                return Reconnect::TimedOut;
+            }
+        }
+    }
+}
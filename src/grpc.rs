@@ -0,0 +1,452 @@
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::error::PayloadError;
+use actix_web::http::header::HeaderMap;
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use anyhow::{Context as _, Result};
+use futures_util::TryStreamExt;
+use hyper::body::{HttpBody, SizeHint};
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, CONTENT_TYPE, HOST, TE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Uri};
+use sqlx::PgPool;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+use crate::errors::AppError;
+use crate::forwards::{ForwardRegistry, Lease, Reconnect};
+use crate::listeners::TLS_HANDSHAKE_TIMEOUT;
+use crate::proxy::admission::{self, Admission, Visitor};
+use crate::proxy::basic_auth::VerifiedCredentials;
+use crate::proxy::body_limit;
+use crate::proxy::capture::{self, BodySample};
+use crate::proxy::rate_limit::RateLimiter;
+use crate::proxy_protocol;
+use crate::request_id::{self, X_REQUEST_ID};
+use crate::settings::Settings;
+use crate::tls::CertResolver;
+
+/// gRPC status codes, see `grpc/doc/statuscodes.md`.
+#[derive(Clone, Copy)]
+enum GrpcStatus {
+    DeadlineExceeded = 4,
+    NotFound = 5,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    Unauthenticated = 16,
+}
+
+impl GrpcStatus {
+    const fn from_error(e: &AppError) -> Self {
+        match e {
+            AppError::NotFound | AppError::TunnelNotFound { .. } => Self::NotFound,
+            AppError::Forbidden => Self::PermissionDenied,
+            AppError::RateLimited { .. } | AppError::PayloadTooLarge { .. } => {
+                Self::ResourceExhausted
+            }
+            AppError::TunnelOffline { .. } | AppError::UpstreamRefused { .. } => Self::Unavailable,
+            AppError::UpstreamTimeout { .. } | AppError::ReconnectTimeout { .. } => {
+                Self::DeadlineExceeded
+            }
+            AppError::Unauthorized { .. } => Self::Unauthenticated,
+            _ => Self::Internal,
+        }
+    }
+}
+
+/// Whether the request is a gRPC call. gRPC-Web carries its trailers in the
+/// body and goes through the HTTP proxy like any other request.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| {
+            content_type == "application/grpc" || content_type.starts_with("application/grpc+")
+        })
+}
+
+/// Pings idle connections this often, so that long streaming calls survive
+/// NATs and dead clients are noticed.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(20);
+/// Past this without an answer to a ping, the connection is closed.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Turns away gRPC calls made to the HTTP listeners, whose client drops the
+/// trailers carrying the status, pointing to the gRPC listener instead.
+pub fn misdirected(settings: &Settings) -> HttpResponse {
+    let message = if settings.grpc.enabled {
+        format!("gRPC is served on port {}", settings.grpc.bind_port)
+    } else {
+        "gRPC is not enabled on this server".to_string()
+    };
+    HttpResponse::Ok()
+        .content_type("application/grpc")
+        .insert_header(("grpc-status", GrpcStatus::Unimplemented as u32))
+        .insert_header(("grpc-message", message))
+        .finish()
+}
+
+/// Response body relayed from upstream with its trailers, such as
+/// `grpc-status`. The lease lives as long as the body, so that streaming
+/// calls count towards least-connections balancing.
+struct RelayedBody {
+    body: Body,
+    lease: Option<Lease>,
+}
+
+impl RelayedBody {
+    fn empty() -> Self {
+        Self {
+            body: Body::empty(),
+            lease: None,
+        }
+    }
+}
+
+impl HttpBody for RelayedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<hyper::HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Answers with a trailers-only response, which is how gRPC servers report
+/// errors before any message was sent.
+fn grpc_error(e: &AppError) -> Response<RelayedBody> {
+    let (_, message) = e.describe();
+    let mut resp = Response::new(RelayedBody::empty());
+    let headers = resp.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    headers.insert(
+        "grpc-status",
+        HeaderValue::from(GrpcStatus::from_error(e) as u16),
+    );
+    if let Ok(message) = HeaderValue::try_from(message) {
+        headers.insert("grpc-message", message);
+    }
+    if let AppError::RateLimited { quota } = e {
+        let mut quota_headers = HeaderMap::new();
+        quota.insert_headers(&mut quota_headers);
+        headers.extend(to_hyper_headers(&quota_headers));
+    }
+    resp
+}
+
+fn to_hyper_headers(headers: &HeaderMap) -> hyper::HeaderMap {
+    let mut hyper_headers = hyper::HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        hyper_headers.append(name.clone(), value.clone());
+    }
+    hyper_headers
+}
+
+struct Shared {
+    settings: Settings,
+    db: PgPool,
+    registry: Arc<ForwardRegistry>,
+    client: Client<HttpConnector>,
+    rate_limiter: Arc<RateLimiter>,
+    verified_credentials: Arc<VerifiedCredentials>,
+    /// Whether the listener terminates TLS.
+    secure: bool,
+}
+
+/// Sends a gRPC call through the connection's tunnel under the same policies
+/// as the HTTP proxy. Calls carry a body, so they are never retried, and are
+/// not cached nor compressed by the proxy.
+#[allow(clippy::too_many_lines)]
+async fn forward(
+    shared: &Shared,
+    peer_addr: SocketAddr,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response<RelayedBody>, AppError> {
+    let Shared { settings, db, .. } = shared;
+    let host = uri
+        .authority()
+        .map(|authority| authority.host().to_string())
+        .or_else(|| {
+            headers
+                .get(HOST)
+                .and_then(|host| host.to_str().ok())
+                .map(str::to_string)
+        })
+        .ok_or(AppError::NotFound)?;
+    let target = admission::resolve_target(db, settings, &host, uri).await?;
+    let connection = &target.connection;
+    let visitor = Visitor {
+        method,
+        uri,
+        headers,
+        peer_addr: Some(peer_addr),
+        secure: shared.secure,
+    };
+    let admission = admission::admit(
+        settings,
+        &shared.rate_limiter,
+        &shared.verified_credentials,
+        connection,
+        visitor,
+    )
+    .await?;
+    let (quota, max_body_size) = match admission {
+        Admission::Forward {
+            quota,
+            max_body_size,
+        } => (quota, max_body_size),
+        Admission::Preflight(resp_headers) => {
+            let mut resp = Response::new(RelayedBody::empty());
+            *resp.status_mut() = StatusCode::NO_CONTENT;
+            *resp.headers_mut() = to_hyper_headers(&resp_headers);
+            return Ok(resp);
+        }
+    };
+
+    let lease = match shared.registry.acquire(connection, None) {
+        Some(lease) => lease,
+        None => match shared.registry.wait_for_reconnect(connection, None).await {
+            Reconnect::Lease(lease) => lease,
+            Reconnect::TimedOut => {
+                return Err(AppError::ReconnectTimeout {
+                    subdomain: connection.subdomain.clone(),
+                })
+            }
+            Reconnect::Offline => {
+                return Err(AppError::TunnelOffline {
+                    subdomain: connection.subdomain.clone(),
+                })
+            }
+        },
+    };
+
+    let request_headers = admission::upstream_headers(settings, visitor, &target)?;
+    let mut sent_headers = request_headers.clone();
+    admission::prepare_request(connection, &mut sent_headers);
+    // gRPC servers refuse calls that do not declare they accept trailers.
+    sent_headers.insert(TE, HeaderValue::from_static("trailers"));
+    sent_headers.remove(HOST);
+
+    let body_sample = connection
+        .capture_requests
+        .then(|| BodySample::new(settings.capture.max_body_size));
+    let body_exceeded = Arc::new(AtomicBool::new(false));
+    let body = body.map_err(|e| PayloadError::Io(io::Error::new(io::ErrorKind::Other, e)));
+    let body = body_limit::limit(body, max_body_size, body_exceeded.clone()).inspect_ok({
+        let body_sample = body_sample.clone();
+        move |chunk| {
+            if let Some(body_sample) = &body_sample {
+                body_sample.record(chunk);
+            }
+        }
+    });
+
+    if let Some(request_id) = request_id::get(headers) {
+        info!(
+            "gRPC request {request_id} sent to {} through forward {} on port {}",
+            connection.subdomain,
+            lease.forward().id,
+            lease.forward().port
+        );
+    }
+    let path_and_query = target
+        .uri
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let proxy_port = lease.forward().port;
+    let mut upstream_req = Request::builder()
+        .method(method.clone())
+        .uri(format!("http://localhost:{proxy_port}{path_and_query}"))
+        .body(Body::wrap_stream(body))
+        .context("Failed to build the gRPC upstream request")?;
+    *upstream_req.headers_mut() = to_hyper_headers(&sent_headers);
+
+    let started_at = Instant::now();
+    let send_result = shared.client.request(upstream_req).await;
+    if let Some(body_sample) = &body_sample {
+        let response_headers = send_result
+            .as_ref()
+            .ok()
+            .map(|resp| HeaderMap::from(resp.headers().clone()));
+        let exchange = capture::Exchange {
+            method,
+            uri: &target.uri,
+            request_headers: &request_headers,
+            status: send_result.as_ref().ok().map(Response::status),
+            response_headers: response_headers.as_ref(),
+            duration: started_at.elapsed(),
+        };
+        capture::record(
+            db,
+            connection,
+            &exchange,
+            body_sample,
+            settings.capture.max_requests,
+        );
+    }
+    if body_exceeded.load(Ordering::Relaxed) {
+        return Err(AppError::PayloadTooLarge {
+            limit: max_body_size,
+        });
+    }
+    let upstream_resp = send_result.map_err(|e| {
+        debug!("gRPC upstream error for {}: {e}", connection.subdomain);
+        AppError::UpstreamRefused {
+            subdomain: connection.subdomain.clone(),
+        }
+    })?;
+
+    let (mut parts, body) = upstream_resp.into_parts();
+    let mut resp_headers = HeaderMap::from(parts.headers);
+    admission::prepare_response(connection, &mut resp_headers);
+    admission::decorate_response(connection, headers, quota.as_ref(), &mut resp_headers);
+    parts.headers = to_hyper_headers(&resp_headers);
+    Ok(Response::from_parts(
+        parts,
+        RelayedBody {
+            body,
+            lease: Some(lease),
+        },
+    ))
+}
+
+async fn relay(
+    shared: Arc<Shared>,
+    peer_addr: SocketAddr,
+    req: Request<Body>,
+) -> Result<Response<RelayedBody>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let mut headers = HeaderMap::from(parts.headers);
+    let request_id = request_id::ensure(&mut headers);
+    let mut resp = forward(
+        &shared,
+        peer_addr,
+        &parts.method,
+        &parts.uri,
+        &headers,
+        body,
+    )
+    .await
+    .unwrap_or_else(|e| grpc_error(&e));
+    if let Some(request_id) = request_id {
+        resp.headers_mut().insert(X_REQUEST_ID.clone(), request_id);
+    }
+    Ok(resp)
+}
+
+/// Dedicated HTTP/2 listener for gRPC. The actix listeners negotiate HTTP/2
+/// too but cannot carry trailers, which gRPC needs for its status. Upstream
+/// servers are reached with prior-knowledge h2c through the tunnel.
+pub async fn serve(
+    settings: Settings,
+    db: PgPool,
+    registry: Arc<ForwardRegistry>,
+    rate_limiter: Arc<RateLimiter>,
+    verified_credentials: Arc<VerifiedCredentials>,
+    cert_resolver: Arc<CertResolver>,
+    cancellation_token: CancellationToken,
+) -> Result<()> {
+    let bind_addr = settings
+        .http
+        .bind_addr
+        .clone()
+        .unwrap_or_else(|| "127.0.0.1".to_string());
+    let listener = TcpListener::bind((bind_addr.as_str(), settings.grpc.bind_port)).await?;
+    let tls_acceptor = settings.http.secure.then(|| {
+        let mut config = cert_resolver.server_config();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    });
+    info!("gRPC listening on {}", listener.local_addr()?);
+
+    let trusted_sources = Arc::new(settings.proxy_protocol.trusted_sources.clone());
+    let shared = Arc::new(Shared {
+        settings,
+        db,
+        registry,
+        client: Client::builder().http2_only(true).build_http(),
+        rate_limiter,
+        verified_credentials,
+        secure: tls_acceptor.is_some(),
+    });
+    loop {
+        let (mut stream, peer_addr) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = cancellation_token.cancelled() => return Ok(()),
+        };
+        let shared = shared.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        let trusted_sources = trusted_sources.clone();
+        tokio::spawn(async move {
+            let peer_addr = match proxy_protocol::accept(&mut stream, &trusted_sources).await {
+                Ok(client_addr) => client_addr.unwrap_or(peer_addr),
+                Err(e) => {
+                    debug!("gRPC connection from {peer_addr} rejected: {e}");
+                    return;
+                }
+            };
+            let service = service_fn(move |req| relay(shared.clone(), peer_addr, req));
+            let mut http = Http::new();
+            http.http2_only(true)
+                .http2_keep_alive_interval(KEEP_ALIVE_INTERVAL)
+                .http2_keep_alive_timeout(KEEP_ALIVE_TIMEOUT);
+            let res = match tls_acceptor {
+                Some(tls_acceptor) => {
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(stream))
+                        .await
+                    {
+                        Ok(Ok(stream)) => http.serve_connection(stream, service).await,
+                        Ok(Err(e)) => {
+                            debug!("gRPC TLS handshake with {peer_addr} failed: {e}");
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("gRPC TLS handshake with {peer_addr} timed out");
+                            return;
+                        }
+                    }
+                }
+                None => http.serve_connection(stream, service).await,
+            };
+            if let Err(e) = res {
+                debug!("gRPC connection with {peer_addr} failed: {e}");
+            }
+        });
+    }
+}
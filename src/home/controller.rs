@@ -1,4 +1,4 @@
-use actix_web::{get, guard, http::header, web, HttpResponse};
+use actix_web::{get, guard, http::header, middleware, web, HttpResponse};
 use askama::Template;
 
 use crate::{errors::AppResponse, home::views, settings::Settings};
@@ -21,6 +21,7 @@ pub fn urls(settings: &Settings, cfg: &mut web::ServiceConfig) {
         web::scope("")
             .guard(guard::Host(api_host.to_string()))
             .guard(guard::Header(header::ACCEPT.as_str(), "text/html"))
+            .wrap(middleware::Compress::default())
             .service(index),
     );
 }
@@ -0,0 +1,89 @@
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_http::error::DispatchError;
+use actix_http::{Extensions, Protocol};
+use ipnet::IpNet;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::proxy_protocol;
+
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Connection data of the requests received on the TLS listener.
+#[derive(Clone, Copy)]
+pub struct TlsConnection;
+
+/// Start of the HTTP/2 connection preface sent by prior-knowledge clients.
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2";
+/// Past this, a plain connection is handed to HTTP/1, whose dispatcher has
+/// its own request timeout.
+const PREFACE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Peeking again returns at once while no new byte came, so partial prefaces
+/// are looked at again after this delay.
+const PREFACE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Whether the connection opens with the HTTP/2 preface, without consuming
+/// it. Peeks until enough bytes came to tell, or the peer stopped sending.
+async fn has_h2_preface(stream: &TcpStream) -> io::Result<bool> {
+    let mut preface = [0; H2_PREFACE.len()];
+    loop {
+        let len = stream.peek(&mut preface).await?;
+        if len == 0 || len == preface.len() || !H2_PREFACE.starts_with(&preface[..len]) {
+            return Ok(preface[..len] == *H2_PREFACE);
+        }
+        tokio::time::sleep(PREFACE_POLL_INTERVAL).await;
+    }
+}
+
+/// Prepares a plain connection for `HttpService`, in place of `tcp()` and
+/// `tcp_auto_h2c()` which only know the socket address.
+pub async fn accept_tcp(
+    mut stream: TcpStream,
+    trusted_sources: Arc<Vec<IpNet>>,
+    h2c: bool,
+) -> Result<(TcpStream, Protocol, Option<SocketAddr>), DispatchError> {
+    let peer_addr = proxy_protocol::accept(&mut stream, &trusted_sources)
+        .await
+        .map_err(DispatchError::Io)?;
+    let is_h2 = h2c
+        && tokio::time::timeout(PREFACE_TIMEOUT, has_h2_preface(&stream))
+            .await
+            .unwrap_or(Ok(false))
+            .map_err(DispatchError::Io)?;
+    let protocol = if is_h2 {
+        Protocol::Http2
+    } else {
+        Protocol::Http1
+    };
+    Ok((stream, protocol, peer_addr))
+}
+
+/// Prepares a TLS connection for `HttpService`, in place of `rustls()`.
+pub async fn accept_tls(
+    mut stream: TcpStream,
+    trusted_sources: Arc<Vec<IpNet>>,
+    tls_acceptor: TlsAcceptor,
+) -> Result<(TlsStream<TcpStream>, Protocol, Option<SocketAddr>), DispatchError> {
+    let peer_addr = proxy_protocol::accept(&mut stream, &trusted_sources)
+        .await
+        .map_err(DispatchError::Io)?;
+    let stream = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(stream))
+        .await
+        .map_err(|_| DispatchError::Io(io::Error::from(ErrorKind::TimedOut)))?
+        .map_err(DispatchError::Io)?;
+    let protocol = if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+        Protocol::Http2
+    } else {
+        Protocol::Http1
+    };
+    Ok((stream, protocol, peer_addr))
+}
+
+/// Marks the connections accepted by `accept_tls`, for `on_connect_ext`.
+pub fn mark_tls(_: &TlsStream<TcpStream>, data: &mut Extensions) {
+    data.insert(TlsConnection);
+}
@@ -1,18 +1,35 @@
+mod acme;
+mod captures;
 mod conf;
 mod connections;
+mod domains;
 mod errors;
+mod forwards;
+mod grpc;
 mod home;
+mod listeners;
 mod proxy;
+mod proxy_protocol;
+mod request_id;
+mod routes;
 mod settings;
 mod sshd;
+mod tls;
 mod util;
 
+use actix_http::HttpService;
+use actix_service::{fn_service, map_config, ServiceFactoryExt};
+use actix_web::dev::AppConfig;
 use actix_web::middleware::TrailingSlash::Trim;
-use actix_web::{middleware, web, App, HttpServer};
+use actix_web::{middleware, web, App};
 use anyhow::Result;
 use futures_util::future::join_all;
 use sqlx::postgres::PgPoolOptions;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
 use tokio::signal;
+use tokio_rustls::TlsAcceptor;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, info};
 
@@ -42,6 +59,47 @@ fn sshd_server_task(
     tokio::task::spawn(async move { sshd_server.start(cancellation_token).await })
 }
 
+fn tls_reload_task(
+    cert_resolver: Arc<tls::CertResolver>,
+    settings: settings::Settings,
+    db_pool: sqlx::PgPool,
+    cancellation_token: CancellationToken,
+) -> tokio::task::JoinHandle<Result<()>> {
+    tokio::task::spawn(cert_resolver.reload_periodically(settings, db_pool, cancellation_token))
+}
+
+fn grpc_server_task(
+    settings: settings::Settings,
+    db_pool: sqlx::PgPool,
+    registry: Arc<forwards::ForwardRegistry>,
+    rate_limiter: Arc<proxy::rate_limit::RateLimiter>,
+    verified_credentials: Arc<proxy::basic_auth::VerifiedCredentials>,
+    cert_resolver: Arc<tls::CertResolver>,
+    cancellation_token: CancellationToken,
+) -> tokio::task::JoinHandle<Result<()>> {
+    tokio::task::spawn(grpc::serve(
+        settings,
+        db_pool,
+        registry,
+        rate_limiter,
+        verified_credentials,
+        cert_resolver,
+        cancellation_token,
+    ))
+}
+
+fn acme_renewal_task(
+    settings: settings::Settings,
+    db_pool: sqlx::PgPool,
+    cancellation_token: CancellationToken,
+) -> tokio::task::JoinHandle<Result<()>> {
+    tokio::task::spawn(acme::renewer::renew_periodically(
+        settings,
+        db_pool,
+        cancellation_token,
+    ))
+}
+
 #[actix_web::main]
 async fn main() -> Result<()> {
     env_logger::init();
@@ -56,56 +114,154 @@ async fn main() -> Result<()> {
         .execute(&db_pool)
         .await?;
 
-    let sshd_server = sshd::Server::new(settings.clone(), db_pool.clone())?;
+    let registry = Arc::new(forwards::ForwardRegistry::new(Duration::from_secs(
+        settings.sshd.reconnect_grace_secs,
+    )));
+    let sshd_server = sshd::Server::new(settings.clone(), db_pool.clone(), registry.clone())?;
+    let cert_resolver = Arc::new(tls::CertResolver::default());
+    if settings.http.secure {
+        cert_resolver.reload(&settings, &db_pool).await?;
+    }
+    let tls_db_pool = db_pool.clone();
+    let acme_db_pool = db_pool.clone();
+    let grpc_db_pool = db_pool.clone();
 
     let shared_settings = web::Data::new(settings.clone());
     let db_pool = web::Data::new(db_pool);
+    let shared_registry = web::Data::from(registry.clone());
+    let shared_cache = web::Data::new(proxy::cache::ResponseCache::new(&settings.cache));
+    // Shared with the gRPC listener, so limits and credentials apply across both.
+    let rate_limiter = Arc::new(proxy::rate_limit::RateLimiter::default());
+    let verified_credentials = Arc::new(proxy::basic_auth::VerifiedCredentials::default());
+    let shared_rate_limiter = web::Data::from(rate_limiter.clone());
+    let shared_verified_credentials = web::Data::from(verified_credentials.clone());
 
-    let server = HttpServer::new(move || {
+    let bind_addr = settings
+        .http
+        .bind_addr
+        .clone()
+        .unwrap_or_else(|| "127.0.0.1".to_string());
+    let expect_db_pool = db_pool.clone();
+    let expect_settings = shared_settings.clone();
+    let expect = move || {
+        let db_pool = expect_db_pool.clone();
+        let settings = expect_settings.clone();
+        fn_service(move |req| proxy::controller::expect(req, db_pool.clone(), settings.clone()))
+    };
+    let app = move || {
         App::new()
             .app_data(db_pool.clone())
             .app_data(shared_settings.clone())
+            .app_data(shared_registry.clone())
+            .app_data(shared_cache.clone())
+            .app_data(shared_rate_limiter.clone())
+            .app_data(shared_verified_credentials.clone())
+            .wrap(middleware::ErrorHandlers::new().default_handler(errors::render_errors))
+            .app_data(web::Data::new(proxy::upstream::client(
+                &shared_settings.upstream,
+            )))
             .wrap(middleware::NormalizePath::new(Trim))
             .wrap(middleware::Logger::new(
-                r#"%a %{X-Real-IP}i %t "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#,
+                r#"%a %{X-Real-IP}i %{X-Request-Id}i %t "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#,
             ))
-            .wrap(middleware::Compress::default())
+            .wrap_fn(request_id::assign)
             .configure(|cfg| home::controller::urls(&shared_settings, cfg))
             .configure(|cfg| conf::controller::urls(&shared_settings, cfg))
             .configure(|cfg| connections::controller::urls(&shared_settings, cfg))
             .configure(|cfg| proxy::controller::urls(&shared_settings, cfg))
-    })
-    .disable_signals()
-    .bind((
-        settings
-            .http
-            .bind_addr
-            .unwrap_or_else(|| "127.0.0.1".to_string()),
-        settings.http.bind_port.unwrap_or(8080),
-    ))?;
-    info!(
-        "Server listening on {:?}",
-        server
-            .addrs_with_scheme()
-            .into_iter()
-            .map(|(addr, scheme)| { format!("{scheme}://{addr}") })
-            .collect::<Vec<_>>()
-            .join(", ")
-    );
+    };
+    // HttpServer does not take an expectation handler, which is needed to
+    // turn away oversized uploads before `100 Continue` is sent, nor lets the
+    // PROXY protocol header be read before HTTP.
+    let trusted_sources = Arc::new(settings.proxy_protocol.trusted_sources.clone());
+    let http_port = settings.http.bind_port.unwrap_or(8080);
+    let h2c = settings.http.h2c;
+    let mut server = actix_server::Server::build().disable_signals().bind(
+        "http",
+        (bind_addr.as_str(), http_port),
+        {
+            let (app, expect, trusted_sources) =
+                (app.clone(), expect.clone(), trusted_sources.clone());
+            move || {
+                let trusted_sources = trusted_sources.clone();
+                fn_service(move |stream: TcpStream| {
+                    listeners::accept_tcp(stream, trusted_sources.clone(), h2c)
+                })
+                .and_then(
+                    HttpService::build()
+                        .expect(expect())
+                        .finish(map_config(app(), |_| AppConfig::default())),
+                )
+            }
+        },
+    )?;
+    info!("Server listening on http://{bind_addr}:{http_port}");
+    if settings.http.secure {
+        let mut tls_config = cert_resolver.server_config();
+        tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let tls_acceptor = TlsAcceptor::from(Arc::new(tls_config));
+        server = server.bind(
+            "https",
+            (bind_addr.as_str(), settings.tls.bind_port),
+            move || {
+                let (trusted_sources, tls_acceptor) =
+                    (trusted_sources.clone(), tls_acceptor.clone());
+                fn_service(move |stream: TcpStream| {
+                    listeners::accept_tls(stream, trusted_sources.clone(), tls_acceptor.clone())
+                })
+                .and_then(
+                    HttpService::build()
+                        .expect(expect())
+                        .on_connect_ext(listeners::mark_tls)
+                        .finish(map_config(app(), |_| AppConfig::default())),
+                )
+            },
+        )?;
+        info!(
+            "Server listening on https://{bind_addr}:{}",
+            settings.tls.bind_port
+        );
+    }
     let cancellation_token = CancellationToken::new();
-    let tasks = vec![
+    let mut tasks = vec![
         http_server_task(server.run(), cancellation_token.clone()),
         sshd_server_task(sshd_server, cancellation_token.clone()),
-        tokio::task::spawn(async move {
-            signal::ctrl_c()
-                .await
-                .map(|_| {
-                    debug!("received ctrl-c, should now shutdown");
-                    cancellation_token.cancel();
-                })
-                .map_err(Into::into)
-        }),
     ];
+    if settings.grpc.enabled {
+        tasks.push(grpc_server_task(
+            settings.clone(),
+            grpc_db_pool,
+            registry,
+            rate_limiter,
+            verified_credentials,
+            cert_resolver.clone(),
+            cancellation_token.clone(),
+        ));
+    }
+    if settings.http.secure {
+        tasks.push(tls_reload_task(
+            cert_resolver,
+            settings.clone(),
+            tls_db_pool,
+            cancellation_token.clone(),
+        ));
+    }
+    if settings.acme.enabled {
+        tasks.push(acme_renewal_task(
+            settings.clone(),
+            acme_db_pool,
+            cancellation_token.clone(),
+        ));
+    }
+    tasks.push(tokio::task::spawn(async move {
+        signal::ctrl_c()
+            .await
+            .map(|_| {
+                debug!("received ctrl-c, should now shutdown");
+                cancellation_token.cancel();
+            })
+            .map_err(Into::into)
+    }));
     let results = join_all(tasks).await;
     results
         .into_iter()
@@ -0,0 +1,202 @@
+use std::net::SocketAddr;
+
+use actix_web::http::header::{
+    HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONNECTION, PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION, TE, TRAILER, TRANSFER_ENCODING,
+};
+use actix_web::http::{Method, Uri};
+use anyhow::Context;
+use sqlx::PgPool;
+
+use crate::connections::models::{Connection, HeaderRuleTarget};
+use crate::errors::AppError;
+use crate::routes::models::Route;
+use crate::settings::Settings;
+use crate::util::extract_subdomain;
+
+use super::basic_auth::{self, VerifiedCredentials};
+use super::body_limit;
+use super::client_ip::client_ip;
+use super::cors;
+use super::forwarded;
+use super::header_rules;
+use super::ip_filter;
+use super::rate_limit::{Quota, RateLimiter};
+use super::sticky;
+use super::trace_context;
+
+static X_FORWARDED_PREFIX: HeaderName = HeaderName::from_static("x-forwarded-prefix");
+
+pub const HOP_BY_HOP_HEADERS: [HeaderName; 6] = [
+    CONNECTION,
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+];
+
+/// The parts of a request the proxy policies look at, whichever listener
+/// received it. `peer_addr` is the one given by the PROXY protocol, if any,
+/// and `secure` tells whether the listener terminated TLS.
+#[derive(Clone, Copy)]
+pub struct Visitor<'a> {
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+    pub headers: &'a HeaderMap,
+    pub peer_addr: Option<SocketAddr>,
+    pub secure: bool,
+}
+
+pub struct Target {
+    pub connection: Connection,
+    pub uri: Uri,
+    pub stripped_prefix: Option<String>,
+}
+
+/// Picks the first route of the host whose prefix matches the path, falling
+/// back to the connection owning the host.
+pub async fn resolve_target(
+    db: &PgPool,
+    settings: &Settings,
+    host: &str,
+    uri: &Uri,
+) -> Result<Target, AppError> {
+    let hostname = host.split(':').next().unwrap_or(host).to_ascii_lowercase();
+    let routes = Route::get_all_for_hostname(db, &hostname).await?;
+    if let Some(route) = routes.iter().find(|route| route.matches(uri.path())) {
+        let connection = Connection::get(db, &route.connection_id)
+            .await
+            .map_err(|_| AppError::NotFound)?;
+        let path = route.forwarded_path(uri.path());
+        let path_and_query = match uri.query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path.to_string(),
+        };
+        return Ok(Target {
+            connection,
+            uri: Uri::try_from(path_and_query).context("Failed to rewrite routed path")?,
+            stripped_prefix: (path != uri.path()).then(|| route.path_prefix.clone()),
+        });
+    }
+
+    let connection = Connection::get_by_host(db, host, settings)
+        .await
+        .map_err(|_| AppError::TunnelNotFound {
+            subdomain: extract_subdomain(&hostname, settings).unwrap_or(hostname),
+        })?;
+    Ok(Target {
+        connection,
+        uri: uri.clone(),
+        stripped_prefix: None,
+    })
+}
+
+pub enum Admission {
+    /// The request may go to the tunnel, with its body limited to
+    /// `max_body_size` bytes.
+    Forward {
+        quota: Option<Quota>,
+        max_body_size: usize,
+    },
+    /// A CORS preflight, answered with these headers and no content.
+    Preflight(HeaderMap),
+}
+
+/// Runs the checks every request of a connection goes through before it is
+/// sent to the tunnel: IP filter, rate limits, CORS preflights, basic auth
+/// and the announced body length.
+pub async fn admit(
+    settings: &Settings,
+    rate_limiter: &RateLimiter,
+    verified_credentials: &VerifiedCredentials,
+    connection: &Connection,
+    visitor: Visitor<'_>,
+) -> Result<Admission, AppError> {
+    let visitor_ip = client_ip(
+        visitor.peer_addr,
+        visitor.headers,
+        &settings.http.trusted_proxies,
+    );
+    ip_filter::authorize(connection, visitor_ip)?;
+    let quota = rate_limiter.check(connection, visitor_ip)?;
+    // Preflights carry no credentials, so they are answered before basic auth.
+    if let Some(resp_headers) = connection
+        .cors
+        .as_ref()
+        .and_then(|cors| cors::preflight(cors, visitor.method, visitor.headers))
+    {
+        return Ok(Admission::Preflight(resp_headers));
+    }
+    basic_auth::authorize(connection, verified_credentials, visitor.headers).await?;
+    let max_body_size = body_limit::max_body_size(connection, settings);
+    body_limit::check_content_length(visitor.headers, max_body_size)?;
+    Ok(Admission::Forward {
+        quota,
+        max_body_size,
+    })
+}
+
+/// The visitor's headers as the tunnelled app gets them, with forwarding and
+/// trace context headers added and the balancing cookie removed.
+pub fn upstream_headers(
+    settings: &Settings,
+    visitor: Visitor<'_>,
+    target: &Target,
+) -> Result<HeaderMap, AppError> {
+    let mut headers = visitor.headers.clone();
+    sticky::remove_cookie(&mut headers);
+    forwarded::set_forwarding_headers(
+        visitor.peer_addr,
+        visitor.uri,
+        visitor.secure,
+        settings,
+        &mut headers,
+    );
+    trace_context::propagate(&mut headers);
+    if let Some(stripped_prefix) = &target.stripped_prefix {
+        headers.insert(
+            X_FORWARDED_PREFIX.clone(),
+            HeaderValue::try_from(stripped_prefix.as_str()).context("Invalid route prefix")?,
+        );
+    }
+    Ok(headers)
+}
+
+/// Drops the headers meant for the proxy and applies the connection's
+/// request header rules, right before the request is sent.
+pub fn prepare_request(connection: &Connection, headers: &mut HeaderMap) {
+    for header in HOP_BY_HOP_HEADERS {
+        headers.remove(header);
+    }
+    if connection.requires_basic_auth() {
+        headers.remove(AUTHORIZATION);
+    }
+    header_rules::apply(&connection.header_rules, HeaderRuleTarget::Request, headers);
+}
+
+/// Applies the connection's response header rules to what upstream sent.
+pub fn prepare_response(connection: &Connection, headers: &mut HeaderMap) {
+    headers.remove(CONNECTION);
+    header_rules::apply(
+        &connection.header_rules,
+        HeaderRuleTarget::Response,
+        headers,
+    );
+}
+
+/// Adds what the proxy itself advertises to every response of the
+/// connection: the rate limit quota and the CORS headers.
+pub fn decorate_response(
+    connection: &Connection,
+    req_headers: &HeaderMap,
+    quota: Option<&Quota>,
+    resp_headers: &mut HeaderMap,
+) {
+    if let Some(quota) = quota {
+        quota.insert_headers(resp_headers);
+    }
+    if let Some(cors) = &connection.cors {
+        cors::apply(cors, req_headers, resp_headers);
+    }
+}
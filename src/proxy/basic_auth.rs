@@ -0,0 +1,113 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use actix_web::http::header::{HeaderMap, AUTHORIZATION};
+use actix_web::web;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::connections::models::Connection;
+use crate::errors::AppError;
+
+/// Past this many verified credentials, a connection starts over.
+const MAX_VERIFIED_PER_CONNECTION: usize = 64;
+
+fn parse_basic_credentials(header_value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = header_value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_owned(), password.to_owned()))
+}
+
+/// Credentials that already passed the Argon2 check, kept as digests salted
+/// with the stored hash so that changing the password invalidates them.
+#[derive(Default)]
+pub struct VerifiedCredentials {
+    digests: Mutex<HashMap<Uuid, HashSet<[u8; 32]>>>,
+}
+
+impl VerifiedCredentials {
+    fn digest(password_hash: &str, username: &str, password: &str) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(password_hash)
+            .chain_update([0])
+            .chain_update(username)
+            .chain_update([0])
+            .chain_update(password)
+            .finalize()
+            .into()
+    }
+
+    fn contains(&self, connection_id: Uuid, digest: &[u8; 32]) -> bool {
+        self.digests.lock().is_ok_and(|digests| {
+            digests
+                .get(&connection_id)
+                .is_some_and(|verified| verified.contains(digest))
+        })
+    }
+
+    fn insert(&self, connection_id: Uuid, digest: [u8; 32]) {
+        let Ok(mut digests) = self.digests.lock() else {
+            return;
+        };
+        let verified = digests.entry(connection_id).or_default();
+        if verified.len() >= MAX_VERIFIED_PER_CONNECTION {
+            verified.clear();
+        }
+        verified.insert(digest);
+    }
+}
+
+/// Checks the value of an `Authorization` header against the credentials of
+/// the connection, if it has any.
+pub async fn is_authorized(
+    connection: &Connection,
+    verified: &VerifiedCredentials,
+    authorization: Option<&str>,
+) -> Result<bool, AppError> {
+    let (Some(expected_username), Some(password_hash)) = (
+        connection.basic_auth_username.clone(),
+        connection.basic_auth_password_hash.clone(),
+    ) else {
+        return Ok(!connection.requires_basic_auth());
+    };
+    let Some((username, password)) = authorization.and_then(parse_basic_credentials) else {
+        return Ok(false);
+    };
+
+    let digest = VerifiedCredentials::digest(&password_hash, &username, &password);
+    if verified.contains(connection.id, &digest) {
+        return Ok(true);
+    }
+    let is_valid = web::block(move || {
+        Connection::verify_basic_auth(&expected_username, &password_hash, &username, &password)
+    })
+    .await
+    .map_err(actix_web::Error::from)?;
+    if is_valid {
+        verified.insert(connection.id, digest);
+    }
+    Ok(is_valid)
+}
+
+pub async fn authorize(
+    connection: &Connection,
+    verified: &VerifiedCredentials,
+    req_headers: &HeaderMap,
+) -> Result<(), AppError> {
+    let authorization = req_headers
+        .get(AUTHORIZATION)
+        .and_then(|header_value| header_value.to_str().ok());
+    if is_authorized(connection, verified, authorization).await? {
+        Ok(())
+    } else {
+        Err(AppError::Unauthorized {
+            realm: connection.subdomain.clone(),
+        })
+    }
+}
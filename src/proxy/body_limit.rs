@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use actix_web::error::PayloadError;
+use actix_web::http::header::{HeaderMap, CONTENT_LENGTH};
+use actix_web::web::Bytes;
+use futures_util::{Stream, StreamExt};
+
+use crate::connections::models::Connection;
+use crate::errors::AppError;
+use crate::settings::Settings;
+
+/// Largest request body accepted for the connection, its own limit taking
+/// precedence over the global one.
+pub fn max_body_size(connection: &Connection, settings: &Settings) -> usize {
+    connection
+        .max_body_size
+        .map_or(settings.http.max_body_size, |max_body_size| {
+            usize::try_from(max_body_size).unwrap_or(usize::MAX)
+        })
+}
+
+/// Rejects a request whose announced length is already over the limit,
+/// before any of its body is read.
+pub fn check_content_length(headers: &HeaderMap, max_body_size: usize) -> Result<(), AppError> {
+    let content_length = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|header_value| header_value.to_str().ok())
+        .and_then(|content_length| content_length.parse::<u64>().ok());
+    match content_length {
+        Some(content_length) if content_length > max_body_size as u64 => {
+            Err(AppError::PayloadTooLarge {
+                limit: max_body_size,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Fails the body once more than `max_body_size` bytes went through, which
+/// covers chunked uploads and lying `Content-Length` headers. `exceeded` is
+/// set so the caller can tell the overflow from an upstream failure.
+pub fn limit<S>(
+    body: S,
+    max_body_size: usize,
+    exceeded: Arc<AtomicBool>,
+) -> impl Stream<Item = Result<Bytes, PayloadError>>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>>,
+{
+    let mut received = 0_usize;
+    body.map(move |chunk| {
+        let chunk = chunk?;
+        received = received.saturating_add(chunk.len());
+        if received > max_body_size {
+            exceeded.store(true, Ordering::Relaxed);
+            return Err(PayloadError::Overflow);
+        }
+        Ok(chunk)
+    })
+}
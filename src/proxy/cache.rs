@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::http::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH,
+    CONTENT_TYPE, ETAG, IF_NONE_MATCH, SET_COOKIE, VARY,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use uuid::Uuid;
+
+use crate::connections::models::Connection;
+use crate::settings;
+
+static X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+/// Connection and host plus path of the visitor's request.
+pub type Key = (Uuid, String);
+
+#[derive(Default)]
+struct Directives {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+fn directives(headers: &HeaderMap) -> Directives {
+    let mut directives = Directives::default();
+    for value in headers
+        .get_all(CACHE_CONTROL)
+        .filter_map(|value| value.to_str().ok())
+    {
+        for directive in value.split(',') {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let seconds = argument.and_then(|argument| argument.parse().ok());
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "max-age" => directives.max_age = seconds,
+                "s-maxage" => directives.s_maxage = seconds,
+                _ => {}
+            }
+        }
+    }
+    directives
+}
+
+fn vary_names(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(VARY)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// How long a response stays fresh, or `None` if a shared cache must not
+/// store it. `no-cache` responses are stored but revalidated every time.
+fn freshness(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    let cacheable_status = matches!(
+        status,
+        StatusCode::OK
+            | StatusCode::NON_AUTHORITATIVE_INFORMATION
+            | StatusCode::MOVED_PERMANENTLY
+            | StatusCode::NOT_FOUND
+            | StatusCode::GONE
+    );
+    // Event streams never end, so there is no complete body to store.
+    let is_event_stream = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim_start().starts_with("text/event-stream"));
+    if !cacheable_status
+        || is_event_stream
+        || headers.contains_key(SET_COOKIE)
+        || vary_names(headers).iter().any(|name| name == "*")
+    {
+        return None;
+    }
+
+    let directives = directives(headers);
+    if directives.no_store || directives.private {
+        return None;
+    }
+    if directives.no_cache {
+        return headers.contains_key(ETAG).then_some(Duration::ZERO);
+    }
+    directives
+        .s_maxage
+        .or(directives.max_age)
+        .map(Duration::from_secs)
+}
+
+fn etag_matches(candidates: &str, etag: &str) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    candidates
+        .split(',')
+        .any(|candidate| candidate.trim() == "*" || strip_weak(candidate) == strip_weak(etag))
+}
+
+#[derive(Clone)]
+pub struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+    ttl: Duration,
+}
+
+impl CachedResponse {
+    pub fn is_fresh(&self) -> bool {
+        self.stored_at.elapsed() < self.ttl
+    }
+
+    pub fn etag(&self) -> Option<&HeaderValue> {
+        self.headers.get(ETAG)
+    }
+
+    /// Builds the answer for a visitor, a bodyless 304 if their
+    /// `If-None-Match` matches the stored `ETag`.
+    pub fn respond(&self, req_headers: &HeaderMap, outcome: &'static str) -> HttpResponse {
+        let not_modified = self
+            .etag()
+            .and_then(|etag| etag.to_str().ok())
+            .is_some_and(|etag| {
+                req_headers
+                    .get_all(IF_NONE_MATCH)
+                    .filter_map(|value| value.to_str().ok())
+                    .any(|candidates| etag_matches(candidates, etag))
+            });
+
+        let status = if not_modified {
+            StatusCode::NOT_MODIFIED
+        } else {
+            self.status
+        };
+        let mut resp = HttpResponse::build(status);
+        for (name, value) in &self.headers {
+            resp.append_header((name.clone(), value.clone()));
+        }
+        resp.insert_header((AGE, self.stored_at.elapsed().as_secs().to_string()));
+        resp.insert_header((X_CACHE.clone(), outcome));
+        if not_modified {
+            resp.finish()
+        } else {
+            resp.body(self.body.clone())
+        }
+    }
+}
+
+struct Entry {
+    response: CachedResponse,
+    varied: Vec<(String, Option<HeaderValue>)>,
+    last_used: u64,
+    size: usize,
+}
+
+impl Entry {
+    fn matches(&self, req_headers: &HeaderMap) -> bool {
+        self.varied
+            .iter()
+            .all(|(name, value)| req_headers.get(name.as_str()) == value.as_ref())
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Key, Vec<Entry>>,
+    size: usize,
+    tick: u64,
+}
+
+impl Inner {
+    fn remove_least_recently_used(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .flat_map(|(key, variants)| {
+                variants
+                    .iter()
+                    .enumerate()
+                    .map(move |(index, entry)| (entry.last_used, key, index))
+            })
+            .min_by_key(|(last_used, _, _)| *last_used)
+            .map(|(_, key, index)| (key.clone(), index));
+        let Some((key, index)) = oldest else {
+            return;
+        };
+        if let Some(variants) = self.entries.get_mut(&key) {
+            self.size -= variants.remove(index).size;
+            if variants.is_empty() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// In-memory cache of tunnelled GET responses, shared by every worker and
+/// bounded by `cache.max_size` bytes. The least recently used responses are
+/// evicted first.
+pub struct ResponseCache {
+    inner: Mutex<Inner>,
+    max_size: usize,
+    max_entry_size: usize,
+}
+
+impl ResponseCache {
+    pub fn new(settings: &settings::Cache) -> Self {
+        Self {
+            inner: Mutex::new(Inner::default()),
+            max_size: settings.max_size,
+            max_entry_size: settings.max_entry_size,
+        }
+    }
+
+    /// Returns the cache key of a request the connection allows to cache.
+    /// Requests whose credentials reach the application are never shared.
+    pub fn key(connection: &Connection, req: &HttpRequest, host: &str) -> Option<Key> {
+        let forwards_credentials =
+            req.headers().contains_key(AUTHORIZATION) && !connection.requires_basic_auth();
+        if !connection.cache_responses || req.method() != Method::GET || forwards_credentials {
+            return None;
+        }
+        let path_and_query = req
+            .uri()
+            .path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str());
+        Some((connection.id, format!("{host}{path_and_query}")))
+    }
+
+    /// Finds the stored variant matching the request, fresh or not. Requests
+    /// asking for `no-cache` or `no-store` always go to the tunnel.
+    pub fn lookup(&self, key: &Key, req_headers: &HeaderMap) -> Option<CachedResponse> {
+        let directives = directives(req_headers);
+        if directives.no_cache || directives.no_store {
+            return None;
+        }
+
+        let mut inner = self.inner.lock().ok()?;
+        let Inner { entries, tick, .. } = &mut *inner;
+        *tick += 1;
+        let entry = entries
+            .get_mut(key)?
+            .iter_mut()
+            .find(|entry| entry.matches(req_headers))?;
+        entry.last_used = *tick;
+        Some(entry.response.clone())
+    }
+
+    /// Renews the stored variant after the application answered 304 to a
+    /// conditional request.
+    pub fn revalidate(
+        &self,
+        key: &Key,
+        req_headers: &HeaderMap,
+        not_modified_headers: &HeaderMap,
+    ) -> Option<CachedResponse> {
+        let mut inner = self.inner.lock().ok()?;
+        let entry = inner
+            .entries
+            .get_mut(key)?
+            .iter_mut()
+            .find(|entry| entry.matches(req_headers))?;
+        for name in [CACHE_CONTROL, ETAG] {
+            if let Some(value) = not_modified_headers.get(&name) {
+                entry.response.headers.insert(name, value.clone());
+            }
+        }
+        if let Some(ttl) = freshness(entry.response.status, &entry.response.headers) {
+            entry.response.ttl = ttl;
+        }
+        entry.response.stored_at = Instant::now();
+        Some(entry.response.clone())
+    }
+
+    /// Tees the body of a storable response into the cache as it streams to
+    /// the visitor. Bodies above `cache.max_entry_size` are not kept.
+    pub fn store(
+        self: Arc<Self>,
+        key: Key,
+        req_headers: &HeaderMap,
+        resp: HttpResponse,
+    ) -> HttpResponse {
+        let Some(ttl) = freshness(resp.status(), resp.headers()) else {
+            return resp;
+        };
+        if let BodySize::Sized(size) = resp.body().size() {
+            if !usize::try_from(size).is_ok_and(|size| size <= self.max_entry_size) {
+                return resp;
+            }
+        }
+
+        let varied = vary_names(resp.headers())
+            .into_iter()
+            .map(|name| {
+                let value = req_headers.get(name.as_str()).cloned();
+                (name, value)
+            })
+            .collect();
+        let mut headers = resp.headers().clone();
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(AGE);
+        let pending = Pending {
+            key,
+            status: resp.status(),
+            headers,
+            varied,
+            ttl,
+            body: Vec::new(),
+        };
+
+        let mut resp = resp.map_body(|_, body| {
+            BoxBody::new(Fill {
+                body,
+                pending: Some(pending),
+                cache: self,
+            })
+        });
+        resp.headers_mut()
+            .insert(X_CACHE.clone(), HeaderValue::from_static("MISS"));
+        resp
+    }
+
+    fn insert(&self, key: Key, mut entry: Entry) {
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+        let Inner {
+            entries,
+            size,
+            tick,
+        } = &mut *inner;
+        *tick += 1;
+        entry.last_used = *tick;
+        let variants = entries.entry(key).or_default();
+        if let Some(index) = variants
+            .iter()
+            .position(|variant| variant.varied == entry.varied)
+        {
+            *size -= variants.remove(index).size;
+        }
+        *size += entry.size;
+        variants.push(entry);
+
+        while inner.size > self.max_size {
+            inner.remove_least_recently_used();
+        }
+    }
+
+    /// Drops every stored response of the connection and returns how many
+    /// there were.
+    pub fn purge(&self, connection_id: &Uuid) -> usize {
+        let Ok(mut inner) = self.inner.lock() else {
+            return 0;
+        };
+        let keys = inner
+            .entries
+            .keys()
+            .filter(|(id, _)| id == connection_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut purged = 0;
+        for key in keys {
+            if let Some(variants) = inner.entries.remove(&key) {
+                purged += variants.len();
+                inner.size -= variants.iter().map(|entry| entry.size).sum::<usize>();
+            }
+        }
+        purged
+    }
+}
+
+struct Pending {
+    key: Key,
+    status: StatusCode,
+    headers: HeaderMap,
+    varied: Vec<(String, Option<HeaderValue>)>,
+    ttl: Duration,
+    body: Vec<u8>,
+}
+
+struct Fill {
+    body: BoxBody,
+    pending: Option<Pending>,
+    cache: Arc<ResponseCache>,
+}
+
+impl MessageBody for Fill {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let item = ready!(Pin::new(&mut this.body).poll_next(cx));
+        match &item {
+            Some(Ok(chunk)) => {
+                let fits = this.pending.as_ref().is_some_and(|pending| {
+                    pending.body.len() + chunk.len() <= this.cache.max_entry_size
+                });
+                match &mut this.pending {
+                    Some(pending) if fits => pending.body.extend_from_slice(chunk),
+                    _ => this.pending = None,
+                }
+            }
+            Some(Err(_)) => this.pending = None,
+            None => {
+                if let Some(pending) = this.pending.take() {
+                    let header_size = pending
+                        .headers
+                        .iter()
+                        .map(|(name, value)| name.as_str().len() + value.len())
+                        .sum::<usize>();
+                    let size = pending.key.1.len() + header_size + pending.body.len();
+                    let entry = Entry {
+                        response: CachedResponse {
+                            status: pending.status,
+                            headers: pending.headers,
+                            body: Bytes::from(pending.body),
+                            stored_at: Instant::now(),
+                            ttl: pending.ttl,
+                        },
+                        varied: pending.varied,
+                        last_used: 0,
+                        size,
+                    };
+                    this.cache.insert(pending.key, entry);
+                }
+            }
+        }
+        Poll::Ready(item)
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::http::header::HeaderMap;
+use actix_web::http::{Method, StatusCode, Uri};
+use sqlx::types::chrono::Utc;
+use sqlx::types::Json;
+use sqlx::PgPool;
+use tracing::error;
+
+use crate::captures::models::{header_pairs, CapturedRequest, Uuid};
+use crate::connections::models::Connection;
+
+#[derive(Default)]
+struct Sample {
+    body: Vec<u8>,
+    truncated: bool,
+}
+
+/// Keeps the first bytes of a request body while it is streamed upstream.
+#[derive(Clone)]
+pub struct BodySample {
+    limit: usize,
+    inner: Arc<Mutex<Sample>>,
+}
+
+impl BodySample {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            inner: Arc::default(),
+        }
+    }
+
+    pub fn record(&self, chunk: &[u8]) {
+        let Ok(mut sample) = self.inner.lock() else {
+            return;
+        };
+        let remaining = self.limit.saturating_sub(sample.body.len());
+        if chunk.len() > remaining {
+            sample.truncated = true;
+        }
+        sample
+            .body
+            .extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+    }
+
+    fn take(&self) -> (Vec<u8>, bool) {
+        let sample = self
+            .inner
+            .lock()
+            .map(|mut sample| std::mem::take(&mut *sample))
+            .unwrap_or_default();
+        (sample.body, sample.truncated)
+    }
+}
+
+pub struct Exchange<'a> {
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+    pub request_headers: &'a HeaderMap,
+    pub status: Option<StatusCode>,
+    pub response_headers: Option<&'a HeaderMap>,
+    pub duration: Duration,
+}
+
+/// Stores the exchange in the background so capturing never delays the
+/// proxied response. Also called from the gRPC listener, outside of actix.
+pub fn record(
+    pool: &PgPool,
+    connection: &Connection,
+    exchange: &Exchange<'_>,
+    body_sample: &BodySample,
+    max_requests: i64,
+) {
+    let (request_body, request_body_truncated) = body_sample.take();
+    let captured = CapturedRequest {
+        id: Uuid::new_v4(),
+        connection_id: connection.id,
+        method: exchange.method.to_string(),
+        path: exchange
+            .uri
+            .path_and_query()
+            .map_or_else(|| exchange.uri.path().to_owned(), ToString::to_string),
+        request_headers: Json(header_pairs(exchange.request_headers)),
+        request_body,
+        request_body_truncated,
+        status: exchange.status.map(|status| i32::from(status.as_u16())),
+        response_headers: exchange
+            .response_headers
+            .map(|headers| Json(header_pairs(headers))),
+        duration_ms: i64::try_from(exchange.duration.as_millis()).unwrap_or(i64::MAX),
+        created_at: Utc::now(),
+    };
+
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        if let Err(e) = captured.insert(&pool).await {
+            error!("Failed to store captured request: {e:#?}");
+            return;
+        }
+        if let Err(e) = CapturedRequest::prune(&pool, &captured.connection_id, max_requests).await {
+            error!("Failed to prune captured requests: {e:#?}");
+        }
+    });
+}
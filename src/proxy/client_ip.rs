@@ -0,0 +1,50 @@
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::http::header::{HeaderMap, X_FORWARDED_FOR};
+use ipnet::IpNet;
+
+use crate::util::canonical_ip;
+
+fn parse_forwarded_for_entry(entry: &str) -> Option<IpAddr> {
+    let entry = entry.trim();
+    entry
+        .parse::<IpAddr>()
+        .ok()
+        .or_else(|| entry.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .map(canonical_ip)
+}
+
+pub fn is_trusted(ip: IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(&ip))
+}
+
+/// Returns the visitor address, walking the `X-Forwarded-For` chain from the
+/// right for as long as the hops are trusted proxies.
+pub fn client_ip(
+    peer_addr: Option<SocketAddr>,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let peer_ip = canonical_ip(peer_addr?.ip());
+    if !is_trusted(peer_ip, trusted_proxies) {
+        return Some(peer_ip);
+    }
+
+    let forwarded_for = headers
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+
+    let mut client_ip = peer_ip;
+    for entry in forwarded_for.into_iter().rev() {
+        let Some(ip) = parse_forwarded_for_entry(entry) else {
+            break;
+        };
+        client_ip = ip;
+        if !is_trusted(ip, trusted_proxies) {
+            break;
+        }
+    }
+    Some(client_ip)
+}
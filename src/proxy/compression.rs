@@ -0,0 +1,137 @@
+use actix_http::encoding::{Decoder, Encoder};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{
+    ContentEncoding, HeaderMap, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE,
+};
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use futures_util::future::Either;
+use futures_util::Stream;
+
+use crate::connections::models::{Compression, CompressionMode};
+
+/// Used when a connection does not list content types of its own.
+const DEFAULT_CONTENT_TYPES: [&str; 8] = [
+    "text/html",
+    "text/css",
+    "text/plain",
+    "text/javascript",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "image/svg+xml",
+];
+
+/// Event streams must reach the visitor as soon as each event is sent, which
+/// compressors buffering their output would prevent.
+const EVENT_STREAM: &str = "text/event-stream";
+
+/// Preferred first when the visitor weighs several encodings equally.
+const SUPPORTED_ENCODINGS: [(&str, ContentEncoding); 3] = [
+    ("br", ContentEncoding::Brotli),
+    ("gzip", ContentEncoding::Gzip),
+    ("deflate", ContentEncoding::Deflate),
+];
+
+fn content_type_allowed(compression: &Compression, headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers
+        .get(CONTENT_TYPE)
+        .and_then(|header_value| header_value.to_str().ok())
+    else {
+        return false;
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if essence == EVENT_STREAM {
+        return false;
+    }
+    let matches = |pattern: &str| match pattern.strip_suffix("/*") {
+        Some(kind) => essence.split('/').next() == Some(kind),
+        None => essence == pattern,
+    };
+    if compression.content_types.is_empty() {
+        DEFAULT_CONTENT_TYPES.into_iter().any(matches)
+    } else {
+        compression
+            .content_types
+            .iter()
+            .any(|pattern| matches(&pattern.to_ascii_lowercase()))
+    }
+}
+
+/// Picks the supported encoding with the highest quality in `Accept-Encoding`.
+fn negotiate(req_headers: &HeaderMap) -> Option<ContentEncoding> {
+    let mut best: Option<(ContentEncoding, f32)> = None;
+    for (name, encoding) in SUPPORTED_ENCODINGS {
+        let quality = req_headers
+            .get_all(ACCEPT_ENCODING)
+            .filter_map(|header_value| header_value.to_str().ok())
+            .flat_map(|header_value| header_value.split(','))
+            .filter_map(|item| {
+                let mut params = item.split(';').map(str::trim);
+                let coding = params.next()?;
+                if !coding.eq_ignore_ascii_case(name) && coding != "*" {
+                    return None;
+                }
+                let quality = params
+                    .find_map(|param| param.strip_prefix("q="))
+                    .map_or(Some(1.0), |quality| quality.parse::<f32>().ok())?;
+                Some((coding == "*", quality))
+            })
+            // An explicit entry for the coding overrides the wildcard.
+            .min_by_key(|(is_wildcard, _)| *is_wildcard)
+            .map_or(0.0, |(_, quality)| quality);
+        if quality > 0.0 && !best.is_some_and(|(_, best_quality)| quality <= best_quality) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Decodes the upstream body when the connection asks for identity
+/// responses, leaving other content types encoded as they came.
+pub fn decode<S>(
+    compression: &Compression,
+    resp_headers: &mut HeaderMap,
+    body: S,
+) -> Either<Decoder<S>, S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    if compression.mode != CompressionMode::Decompress
+        || !resp_headers.contains_key(CONTENT_ENCODING)
+        || !content_type_allowed(compression, resp_headers)
+    {
+        return Either::Right(body);
+    }
+    let decoder = Decoder::from_headers(body, resp_headers);
+    resp_headers.remove(CONTENT_ENCODING);
+    resp_headers.remove(CONTENT_LENGTH);
+    Either::Left(decoder)
+}
+
+/// Encodes the response for the visitor when the connection compresses and
+/// upstream sent it unencoded. Event streams never reach the encoder, as
+/// their content type is not allowed.
+pub fn encode(
+    compression: &Compression,
+    req_headers: &HeaderMap,
+    mut resp: HttpResponse,
+) -> HttpResponse {
+    if compression.mode != CompressionMode::Compress
+        || resp.headers().contains_key(CONTENT_ENCODING)
+        || !content_type_allowed(compression, resp.headers())
+    {
+        return resp;
+    }
+    let Some(encoding) = negotiate(req_headers) else {
+        return resp;
+    };
+    resp.headers_mut().remove(CONTENT_LENGTH);
+    resp.map_body(|head, body| Encoder::response(encoding, head, body))
+        .map_into_boxed_body()
+}
@@ -1,145 +1,477 @@
-use crate::connections::models::Connection;
+use crate::acme;
+use crate::connections::models::{BalancingStrategy, Connection};
 use crate::errors::AppError;
 use crate::errors::AppResponse;
+use crate::forwards::{ForwardRegistry, Lease, Reconnect};
+use crate::grpc;
+use crate::listeners::TlsConnection;
+use crate::request_id;
 use crate::settings::Settings;
-use crate::util::extract_subdomain;
+use actix_web::error::PayloadError;
 use actix_web::http::header::HeaderMap;
-use actix_web::http::header::HeaderName;
-use actix_web::http::header::CONNECTION;
-use actix_web::http::header::PROXY_AUTHENTICATE;
-use actix_web::http::header::PROXY_AUTHORIZATION;
-use actix_web::http::header::TE;
-use actix_web::http::header::TRAILER;
-use actix_web::http::header::TRANSFER_ENCODING;
-use actix_web::http::header::X_FORWARDED_FOR;
+use actix_web::http::header::IF_NONE_MATCH;
+use actix_web::http::header::USER_AGENT;
+use actix_web::http::Method;
+use actix_web::http::StatusCode;
 use actix_web::http::Uri;
+use actix_web::web::Bytes;
 use actix_web::HttpResponseBuilder;
 use actix_web::{web, HttpRequest, HttpResponse};
 use anyhow::Context;
 use anyhow::Result;
+use awc::error::{ConnectError, SendRequestError};
+use futures_util::{stream, Stream, TryStreamExt};
 use sqlx::PgPool;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
 
-use super::wildcard_host_guard;
-use super::wildcard_host_guard::get_uri_host;
+use super::admission::{self, Admission, Target, Visitor, HOP_BY_HOP_HEADERS};
+use super::basic_auth::VerifiedCredentials;
+use super::body_limit;
+use super::cache::{CachedResponse, ResponseCache};
+use super::capture::{self, BodySample};
+use super::compression;
+use super::host_guard;
+use super::host_guard::get_uri_host;
+use super::idle_timeout::IdleTimeout;
+use super::offline;
+use super::rate_limit::RateLimiter;
+use super::retry;
+use super::sticky;
 
-const HOP_BY_HOP_HEADERS: [HeaderName; 6] = [
-    CONNECTION,
-    PROXY_AUTHENTICATE,
-    PROXY_AUTHORIZATION,
-    TE,
-    TRAILER,
-    TRANSFER_ENCODING,
-];
-
-static STATIC_X_FORWARDED_FOR: HeaderName = X_FORWARDED_FOR;
-
-fn forward_uri_value(connection: &Connection, req_uri: &Uri) -> Result<Uri> {
+fn forward_uri_value(proxy_port: u16, req_uri: &Uri) -> Result<Uri> {
     let mut uri_parts = req_uri.clone().into_parts();
 
     uri_parts.scheme = Some("http".parse()?);
-    let proxy_host = format!(
-        "localhost:{}",
-        connection
-            .proxy_port
-            .as_ref()
-            .context("No proxy port available for connection")?
-    );
+    let proxy_host = format!("localhost:{proxy_port}");
     uri_parts.authority = Some(proxy_host.parse()?);
     Uri::try_from(uri_parts).context("Failed creating proxy URI from parts")
 }
 
-fn x_forwarded_for_value(req: &HttpRequest) -> String {
-    let mut result = String::new();
-
-    for (key, value) in req.headers() {
-        if key == STATIC_X_FORWARDED_FOR {
-            if let Ok(value) = value.to_str() {
-                result.push_str(value);
-            }
-            break;
+fn copy_except_hop_by_hop(source_headers: &HeaderMap, resp_builder: &mut HttpResponseBuilder) {
+    for header in source_headers {
+        if !HOP_BY_HOP_HEADERS.contains(header.0) {
+            resp_builder.insert_header(header);
         }
     }
+}
 
-    if let Some(peer_addr) = req.peer_addr() {
-        if !result.is_empty() {
-            result.push_str(", ");
+/// Tells a local port that refused or dropped the connection and a slow
+/// upstream apart from failures of the proxy itself. Only failures before
+/// any response head was read count as refused, which makes them retried.
+fn upstream_error(e: SendRequestError, subdomain: &str) -> AppError {
+    let subdomain = subdomain.to_string();
+    match e {
+        SendRequestError::Timeout | SendRequestError::Connect(ConnectError::Timeout) => {
+            AppError::UpstreamTimeout { subdomain }
         }
-        let client_ip_str = &format!("{}", peer_addr.ip());
-        result.push_str(client_ip_str);
+        SendRequestError::Connect(_) | SendRequestError::Send(_) => {
+            AppError::UpstreamRefused { subdomain }
+        }
+        SendRequestError::Response(_) => AppError::UpstreamInvalid { subdomain },
+        e => AppError::Awc(e),
     }
-    result
 }
 
-fn remove_connection_headers(headers: &mut HeaderMap) {
-    headers.remove(CONNECTION);
+#[derive(Clone, Copy)]
+pub struct ForwardRequest<'a> {
+    pub method: &'a Method,
+    pub uri: &'a Uri,
+    pub headers: &'a HeaderMap,
 }
 
-fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
-    for header in HOP_BY_HOP_HEADERS {
-        headers.remove(header);
+/// Sends a request through the connection's tunnel and streams the answer
+/// back. Shared by visitor traffic and replays of captured requests.
+#[allow(clippy::future_not_send)]
+pub async fn forward<S, E>(
+    client: &awc::Client,
+    db: &PgPool,
+    settings: &Settings,
+    connection: &Connection,
+    lease: Lease,
+    request: ForwardRequest<'_>,
+    body: S,
+) -> AppResponse
+where
+    S: Stream<Item = Result<Bytes, E>> + 'static,
+    E: Into<Box<dyn std::error::Error>> + 'static,
+{
+    // Lets the SSH side of a request be found from its id.
+    if let Some(request_id) = request_id::get(request.headers) {
+        info!(
+            "request {request_id} sent to {} through forward {} on port {}",
+            connection.subdomain,
+            lease.forward().id,
+            lease.forward().port
+        );
     }
-}
+    let mut forward_req = client
+        .request(
+            request.method.clone(),
+            forward_uri_value(lease.forward().port, request.uri)?,
+        )
+        .no_decompress();
+    for (name, value) in request.headers {
+        forward_req
+            .headers_mut()
+            .append(name.clone(), value.clone());
+    }
+    forward_req = forward_req.insert_header_if_none((USER_AGENT, ""));
+    admission::prepare_request(connection, forward_req.headers_mut());
 
-fn copy_except_hop_by_hop(source_headers: &HeaderMap, resp_builder: &mut HttpResponseBuilder) {
-    for header in source_headers {
-        if !HOP_BY_HOP_HEADERS.contains(header.0) {
-            resp_builder.insert_header(header);
+    let body_sample = connection
+        .capture_requests
+        .then(|| BodySample::new(settings.capture.max_body_size));
+    let body = body.inspect_ok({
+        let body_sample = body_sample.clone();
+        move |chunk| {
+            if let Some(body_sample) = &body_sample {
+                body_sample.record(chunk);
+            }
         }
+    });
+
+    let started_at = Instant::now();
+    // Only the response head is bounded here, bodies by `IdleTimeout`.
+    let send_result = tokio::time::timeout(
+        Duration::from_secs(settings.upstream.timeout_secs),
+        forward_req.send_stream(body),
+    )
+    .await
+    .unwrap_or(Err(SendRequestError::Timeout));
+    if let Some(body_sample) = &body_sample {
+        let exchange = capture::Exchange {
+            method: request.method,
+            uri: request.uri,
+            request_headers: request.headers,
+            status: send_result.as_ref().ok().map(awc::ClientResponse::status),
+            response_headers: send_result.as_ref().ok().map(awc::ClientResponse::headers),
+            duration: started_at.elapsed(),
+        };
+        capture::record(
+            db,
+            connection,
+            &exchange,
+            body_sample,
+            settings.capture.max_requests,
+        );
     }
+    let backend_resp = send_result.map_err(|e| upstream_error(e, &connection.subdomain))?;
+
+    let mut resp_headers = backend_resp.headers().clone();
+    let mut resp_builder = HttpResponse::build(backend_resp.status());
+
+    // The lease lives as long as the body, so that streamed responses count
+    // towards least-connections balancing.
+    let body = IdleTimeout::new(
+        backend_resp,
+        Duration::from_secs(settings.upstream.idle_timeout_secs),
+    )
+    .map_ok(move |chunk| {
+        let _lease = &lease;
+        chunk
+    });
+    let body = compression::decode(&connection.compression, &mut resp_headers, body);
+    copy_except_hop_by_hop(&resp_headers, &mut resp_builder);
+    let mut resp = resp_builder.streaming(body);
+    admission::prepare_response(connection, resp.headers_mut());
+
+    Ok(compression::encode(
+        &connection.compression,
+        request.headers,
+        resp,
+    ))
 }
 
-#[allow(clippy::future_not_send)]
+#[allow(clippy::future_not_send, clippy::too_many_arguments)]
 pub async fn process(
     req: HttpRequest,
     payload: web::Payload,
+    client: web::Data<awc::Client>,
+    registry: web::Data<ForwardRegistry>,
+    cache: web::Data<ResponseCache>,
+    rate_limiter: web::Data<RateLimiter>,
+    verified_credentials: web::Data<VerifiedCredentials>,
     db: web::Data<PgPool>,
     settings: web::Data<Settings>,
 ) -> AppResponse {
+    if grpc::is_grpc(req.headers()) {
+        return Ok(grpc::misdirected(&settings));
+    }
     let host = get_uri_host(req.head())
         .context("Could parse Host")?
         .to_string();
-    let subdomain = extract_subdomain(&host, &settings)?;
-    let connection = Connection::get_by_subdomain(&db, &subdomain)
-        .await
-        .map_err(|_| AppError::NotFound)?;
-    if connection.proxy_port.is_none() {
-        return Err(AppError::NotFound);
-    }
+    let target = admission::resolve_target(&db, &settings, &host, req.uri()).await?;
+    let result = proxy(
+        &req,
+        payload,
+        client,
+        registry,
+        cache,
+        rate_limiter,
+        verified_credentials,
+        db,
+        settings,
+        &host,
+        &target,
+    )
+    .await;
+    // Error pages of a tunnel carry its CORS headers too, so that its scripts
+    // can read them.
+    Ok(result.unwrap_or_else(|e| {
+        let mut resp = HttpResponse::from_error(e);
+        admission::decorate_response(&target.connection, req.headers(), None, resp.headers_mut());
+        resp
+    }))
+}
 
-    let mut forward_req = awc::Client::new()
-        .request_from(req.uri(), req.head())
-        .no_decompress()
-        .timeout(Duration::from_secs(10))
-        .uri(forward_uri_value(&connection, req.uri())?)
-        .insert_header_if_none((actix_web::http::header::USER_AGENT, ""))
-        .append_header((X_FORWARDED_FOR, x_forwarded_for_value(&req)));
+/// Sends a visitor's request to the tunnel its host and path resolved to.
+#[allow(
+    clippy::future_not_send,
+    clippy::too_many_arguments,
+    clippy::too_many_lines
+)]
+async fn proxy(
+    req: &HttpRequest,
+    payload: web::Payload,
+    client: web::Data<awc::Client>,
+    registry: web::Data<ForwardRegistry>,
+    cache: web::Data<ResponseCache>,
+    rate_limiter: web::Data<RateLimiter>,
+    verified_credentials: web::Data<VerifiedCredentials>,
+    db: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    host: &str,
+    target: &Target,
+) -> AppResponse {
+    let connection = &target.connection;
+    let visitor = Visitor {
+        method: req.method(),
+        uri: req.uri(),
+        headers: req.headers(),
+        peer_addr: req.peer_addr(),
+        secure: req.conn_data::<TlsConnection>().is_some(),
+    };
+    let admission = admission::admit(
+        &settings,
+        &rate_limiter,
+        &verified_credentials,
+        connection,
+        visitor,
+    )
+    .await?;
+    let (quota, max_body_size) = match admission {
+        Admission::Forward {
+            quota,
+            max_body_size,
+        } => (quota, max_body_size),
+        Admission::Preflight(resp_headers) => {
+            let mut resp = HttpResponse::NoContent();
+            for (name, value) in &resp_headers {
+                resp.append_header((name.clone(), value.clone()));
+            }
+            return Ok(resp.finish());
+        }
+    };
 
-    remove_connection_headers(forward_req.headers_mut());
-    remove_hop_by_hop_headers(forward_req.headers_mut());
+    let cache_key = ResponseCache::key(connection, req, host);
+    let cached = cache_key
+        .as_ref()
+        .and_then(|cache_key| cache.lookup(cache_key, req.headers()));
+    if let Some(cached) = cached.as_ref().filter(|cached| cached.is_fresh()) {
+        let mut resp = cached.respond(req.headers(), "HIT");
+        admission::decorate_response(
+            connection,
+            req.headers(),
+            quota.as_ref(),
+            resp.headers_mut(),
+        );
+        return Ok(resp);
+    }
 
-    let backend_resp = forward_req
-        .send_stream(payload)
-        .await?
-        .timeout(Duration::from_secs(10));
+    let sticky_id = sticky::forward_id(req);
+    let lease = match (registry.acquire(connection, sticky_id), &cached) {
+        (Some(lease), _) => lease,
+        (None, Some(cached)) => {
+            let mut resp = cached.respond(req.headers(), "STALE");
+            admission::decorate_response(
+                connection,
+                req.headers(),
+                quota.as_ref(),
+                resp.headers_mut(),
+            );
+            return Ok(resp);
+        }
+        (None, None) => match registry.wait_for_reconnect(connection, sticky_id).await {
+            Reconnect::Lease(lease) => lease,
+            // A client that did not come back within the grace period is
+            // offline too.
+            Reconnect::TimedOut | Reconnect::Offline => {
+                let mut resp = offline::respond(connection)?;
+                admission::decorate_response(
+                    connection,
+                    req.headers(),
+                    quota.as_ref(),
+                    resp.headers_mut(),
+                );
+                return Ok(resp);
+            }
+        },
+    };
+    let mut forward_id = lease.forward().id;
 
-    let mut resp_builder = HttpResponse::build(backend_resp.status());
+    let mut headers = admission::upstream_headers(&settings, visitor, &target)?;
+    if let Some(etag) = cached.as_ref().and_then(CachedResponse::etag) {
+        headers.insert(IF_NONE_MATCH, etag.clone());
+    }
+    let forward_request = ForwardRequest {
+        method: req.method(),
+        uri: &target.uri,
+        headers: &headers,
+    };
+    let body_exceeded = Arc::new(AtomicBool::new(false));
+    let body = body_limit::limit(payload, max_body_size, body_exceeded.clone());
+    let mut result = forward(
+        &client,
+        &db,
+        &settings,
+        connection,
+        lease,
+        forward_request,
+        body,
+    )
+    .await;
+    if body_exceeded.load(Ordering::Relaxed) {
+        return Err(AppError::PayloadTooLarge {
+            limit: max_body_size,
+        });
+    }
 
-    copy_except_hop_by_hop(backend_resp.headers(), &mut resp_builder);
+    // A channel that cannot be opened surfaces as a refused connection before
+    // any response byte, typically while the client reconnects.
+    let mut went_offline = false;
+    if retry::is_retryable(&settings.retry, req) {
+        let mut attempt = 1;
+        while attempt < settings.retry.attempts
+            && matches!(result, Err(AppError::UpstreamRefused { .. }))
+        {
+            tokio::time::sleep(retry::backoff(&settings.retry, attempt)).await;
+            attempt += 1;
+            // The client may be reconnecting, wait for it as for the first attempt.
+            let lease = match registry.acquire(connection, sticky_id) {
+                Some(lease) => lease,
+                None => match registry.wait_for_reconnect(connection, sticky_id).await {
+                    Reconnect::Lease(lease) => lease,
+                    Reconnect::TimedOut | Reconnect::Offline => {
+                        went_offline = true;
+                        break;
+                    }
+                },
+            };
+            debug!(
+                "retrying {} {} on {} (attempt {attempt})",
+                req.method(),
+                target.uri,
+                connection.subdomain
+            );
+            forward_id = lease.forward().id;
+            result = forward(
+                &client,
+                &db,
+                &settings,
+                connection,
+                lease,
+                forward_request,
+                stream::empty::<Result<Bytes, PayloadError>>(),
+            )
+            .await;
+        }
+    }
 
-    let mut resp = resp_builder.streaming(backend_resp);
+    // A stale response still beats the offline page.
+    if went_offline && cached.is_none() {
+        let mut resp = offline::respond(connection)?;
+        admission::decorate_response(
+            connection,
+            req.headers(),
+            quota.as_ref(),
+            resp.headers_mut(),
+        );
+        return Ok(resp);
+    }
 
-    remove_connection_headers(resp.headers_mut());
+    let mut resp = match (result, cache_key, cached) {
+        (Ok(resp), Some(cache_key), Some(cached)) if resp.status() == StatusCode::NOT_MODIFIED => {
+            cache
+                .revalidate(&cache_key, req.headers(), resp.headers())
+                .unwrap_or(cached)
+                .respond(req.headers(), "REVALIDATED")
+        }
+        (
+            Err(AppError::UpstreamRefused { .. } | AppError::UpstreamTimeout { .. }),
+            _,
+            Some(cached),
+        ) => cached.respond(req.headers(), "STALE"),
+        (result, Some(cache_key), _) => cache.into_inner().store(cache_key, req.headers(), result?),
+        (result, None, _) => result?,
+    };
 
+    let is_sticky = connection
+        .load_balancing
+        .as_ref()
+        .is_some_and(|load_balancing| load_balancing.strategy == BalancingStrategy::Sticky);
+    if is_sticky && sticky_id != Some(forward_id) {
+        sticky::set_cookie(&mut resp, forward_id);
+    }
+    admission::decorate_response(
+        connection,
+        req.headers(),
+        quota.as_ref(),
+        resp.headers_mut(),
+    );
     Ok(resp)
 }
 
+/// Answers `Expect: 100-continue` with a 413 instead of `100 Continue` when
+/// the announced body is over the tunnel's limit, so the client never sends
+/// it. Requests not aimed at a tunnel are left to the app.
+#[allow(clippy::future_not_send)]
+pub async fn expect(
+    req: actix_http::Request,
+    db: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+) -> Result<actix_http::Request, actix_web::Error> {
+    let Some(host) = get_uri_host(req.head()) else {
+        return Ok(req);
+    };
+    let head = req.head();
+    let Ok(Target { connection, .. }) =
+        admission::resolve_target(&db, &settings, &host, &head.uri).await
+    else {
+        return Ok(req);
+    };
+    let max_body_size = body_limit::max_body_size(&connection, &settings);
+    body_limit::check_content_length(&head.headers, max_body_size)?;
+    Ok(req)
+}
+
 pub fn urls(settings: &Settings, cfg: &mut web::ServiceConfig) {
+    let api_host = settings
+        .http
+        .url
+        .host()
+        .map_or_else(|| panic!("No host found for API URL"), |api_host| api_host);
     cfg.service(
         web::scope("")
-            .guard(wildcard_host_guard::WildcardHostGuard {
-                host: settings.http.vhost_suffix.clone(),
+            .guard(host_guard::NotApiHostGuard {
+                api_host: api_host.to_string(),
             })
+            .route(
+                "/.well-known/acme-challenge/{token}",
+                web::get().to(acme::controller::challenge),
+            )
             .default_service(web::to(process)),
     );
 }
@@ -0,0 +1,114 @@
+use actix_web::http::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use actix_web::http::Method;
+
+use crate::connections::models::Cors;
+
+/// The value of `Access-Control-Allow-Origin` for the request's origin, if
+/// it is allowed. `*` is only sent back as such without credentials.
+fn allowed_origin(cors: &Cors, req_headers: &HeaderMap) -> Option<HeaderValue> {
+    let origin = req_headers.get(ORIGIN)?;
+    let origin_str = origin.to_str().ok()?;
+    let any_origin = cors.allowed_origins.iter().any(|allowed| allowed == "*");
+    if any_origin && !cors.allow_credentials {
+        return Some(HeaderValue::from_static("*"));
+    }
+    cors.allowed_origins
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(origin_str))
+        .then(|| origin.clone())
+}
+
+fn joined_or(values: &[String], requested: Option<&HeaderValue>) -> Option<HeaderValue> {
+    if values.is_empty() {
+        return requested.cloned();
+    }
+    HeaderValue::try_from(values.join(", ")).ok()
+}
+
+/// Merges `Origin` into the `Vary` of the response, in a single value, unless
+/// it already varies on it or on everything.
+fn vary_on_origin(resp_headers: &mut HeaderMap) {
+    let mut fields = resp_headers
+        .get_all(VARY)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(ToOwned::to_owned)
+        .collect::<Vec<_>>();
+    if !fields
+        .iter()
+        .any(|field| field == "*" || field.eq_ignore_ascii_case("origin"))
+    {
+        fields.push("Origin".to_string());
+    }
+    if let Ok(value) = HeaderValue::try_from(fields.join(", ")) {
+        resp_headers.insert(VARY, value);
+    }
+}
+
+/// Answers a preflight request in place of the tunnelled app, which never
+/// sees it, with the headers of a `204 No Content`. Returns `None` for
+/// requests that are not preflights.
+pub fn preflight(cors: &Cors, method: &Method, req_headers: &HeaderMap) -> Option<HeaderMap> {
+    if *method != Method::OPTIONS
+        || !req_headers.contains_key(ORIGIN)
+        || !req_headers.contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return None;
+    }
+
+    let mut resp_headers = HeaderMap::new();
+    vary_on_origin(&mut resp_headers);
+    // Without an allowed origin the browser fails the preflight by itself.
+    let Some(allow_origin) = allowed_origin(cors, req_headers) else {
+        return Some(resp_headers);
+    };
+    resp_headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    if let Some(methods) = joined_or(
+        &cors.allowed_methods,
+        req_headers.get(ACCESS_CONTROL_REQUEST_METHOD),
+    ) {
+        resp_headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+    }
+    if let Some(headers) = joined_or(
+        &cors.allowed_headers,
+        req_headers.get(ACCESS_CONTROL_REQUEST_HEADERS),
+    ) {
+        resp_headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, headers);
+    }
+    if cors.allow_credentials {
+        resp_headers.insert(
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    if let Some(max_age_secs) = cors.max_age_secs {
+        resp_headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age_secs));
+    }
+    Some(resp_headers)
+}
+
+/// Adds the CORS headers of an allowed origin to a forwarded response,
+/// replacing whatever the tunnelled app sent. Other origins get none.
+pub fn apply(cors: &Cors, req_headers: &HeaderMap, resp_headers: &mut HeaderMap) {
+    vary_on_origin(resp_headers);
+    let Some(allow_origin) = allowed_origin(cors, req_headers) else {
+        resp_headers.remove(ACCESS_CONTROL_ALLOW_ORIGIN);
+        resp_headers.remove(ACCESS_CONTROL_ALLOW_CREDENTIALS);
+        return;
+    };
+    resp_headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    if cors.allow_credentials {
+        resp_headers.insert(
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    } else {
+        resp_headers.remove(ACCESS_CONTROL_ALLOW_CREDENTIALS);
+    }
+}
@@ -0,0 +1,112 @@
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::http::header::{
+    HeaderMap, HeaderName, HeaderValue, FORWARDED, HOST, X_FORWARDED_FOR, X_FORWARDED_HOST,
+    X_FORWARDED_PROTO,
+};
+use actix_web::http::Uri;
+
+use crate::settings::Settings;
+use crate::util::canonical_ip;
+
+use super::client_ip::is_trusted;
+
+static X_FORWARDED_PORT: HeaderName = HeaderName::from_static("x-forwarded-port");
+
+fn original_host(headers: &HeaderMap, uri: &Uri) -> Option<String> {
+    headers
+        .get(HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned)
+        .or_else(|| uri.authority().map(ToString::to_string))
+}
+
+fn host_port(host: &str) -> Option<u16> {
+    let (_, port) = host.rsplit_once(':')?;
+    // An IPv6 literal without port ends with `]`, which never parses as a port.
+    port.parse().ok()
+}
+
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{ip}]\""),
+    }
+}
+
+fn forwarded_element(peer_ip: Option<IpAddr>, host: Option<&str>, proto: &str) -> String {
+    let mut pairs = Vec::with_capacity(3);
+    if let Some(ip) = peer_ip {
+        pairs.push(format!("for={}", forwarded_node(ip)));
+    }
+    if let Some(host) = host {
+        pairs.push(format!("host=\"{}\"", host.replace('"', "")));
+    }
+    pairs.push(format!("proto={proto}"));
+    pairs.join(";")
+}
+
+fn append_to_list(headers: &mut HeaderMap, name: &HeaderName, value: &str) {
+    let mut values = headers
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .map(ToOwned::to_owned)
+        .collect::<Vec<_>>();
+    values.push(value.to_owned());
+    if let Ok(value) = HeaderValue::try_from(values.join(", ")) {
+        headers.insert(name.clone(), value);
+    }
+}
+
+fn insert_if_none(headers: &mut HeaderMap, name: &HeaderName, value: &str) {
+    if headers.contains_key(name) {
+        return;
+    }
+    if let Ok(value) = HeaderValue::try_from(value) {
+        headers.insert(name.clone(), value);
+    }
+}
+
+/// Adds `X-Forwarded-*` and RFC 7239 `Forwarded` headers describing the
+/// visitor, `secure` being whether the request came in over TLS. Values set
+/// by a peer listed in `http.trusted_proxies` are kept and extended; anyone
+/// else's are discarded.
+pub fn set_forwarding_headers(
+    peer_addr: Option<SocketAddr>,
+    uri: &Uri,
+    secure: bool,
+    settings: &Settings,
+    headers: &mut HeaderMap,
+) {
+    let peer_ip = peer_addr.map(|addr| canonical_ip(addr.ip()));
+    let trusted = peer_ip.map_or(false, |ip| is_trusted(ip, &settings.http.trusted_proxies));
+    if !trusted {
+        for name in [
+            &X_FORWARDED_FOR,
+            &X_FORWARDED_PROTO,
+            &X_FORWARDED_HOST,
+            &X_FORWARDED_PORT,
+            &FORWARDED,
+        ] {
+            headers.remove(name);
+        }
+    }
+
+    let (proto, default_port) = if secure { ("https", 443) } else { ("http", 80) };
+    let host = original_host(headers, uri);
+    let port = host.as_deref().and_then(host_port).unwrap_or(default_port);
+
+    if let Some(ip) = peer_ip {
+        append_to_list(headers, &X_FORWARDED_FOR, &ip.to_string());
+    }
+    insert_if_none(headers, &X_FORWARDED_PROTO, proto);
+    if let Some(host) = &host {
+        insert_if_none(headers, &X_FORWARDED_HOST, host);
+    }
+    insert_if_none(headers, &X_FORWARDED_PORT, &port.to_string());
+    append_to_list(
+        headers,
+        &FORWARDED,
+        &forwarded_element(peer_ip, host.as_deref(), proto),
+    );
+}
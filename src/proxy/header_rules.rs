@@ -0,0 +1,29 @@
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::connections::models::{HeaderRule, HeaderRuleAction, HeaderRuleTarget};
+
+/// Rules are applied in the order they were configured, so a `remove`
+/// followed by an `append` replaces every existing value.
+pub fn apply(rules: &[HeaderRule], target: HeaderRuleTarget, headers: &mut HeaderMap) {
+    for rule in rules.iter().filter(|rule| rule.target == target) {
+        let Ok(name) = HeaderName::try_from(rule.name.as_str()) else {
+            continue;
+        };
+        let value = rule
+            .value
+            .as_deref()
+            .and_then(|value| HeaderValue::try_from(value).ok());
+        match (rule.action, value) {
+            (HeaderRuleAction::Remove, _) => {
+                headers.remove(name);
+            }
+            (HeaderRuleAction::Set, Some(value)) => {
+                headers.insert(name, value);
+            }
+            (HeaderRuleAction::Append, Some(value)) => {
+                headers.append(name, value);
+            }
+            _ => {}
+        }
+    }
+}
@@ -14,18 +14,21 @@ pub fn get_uri_host(req: &RequestHead) -> Option<String> {
         .or_else(|| req.uri.host().map(ToOwned::to_owned))
 }
 
+/// Matches every host but the API one. Tunnels are reached through the vhost
+/// suffix as well as custom domains only known to the database, so the proxy
+/// resolves the host itself.
 #[doc(hidden)]
-pub struct WildcardHostGuard {
-    pub host: String,
+pub struct NotApiHostGuard {
+    pub api_host: String,
 }
 
-impl Guard for WildcardHostGuard {
+impl Guard for NotApiHostGuard {
     fn check(&self, ctx: &GuardContext<'_>) -> bool {
         let Some(host) = get_uri_host(ctx.head()) else {
             return false;
         };
 
-        debug!("wildcard_host_guard: uri_host {host:?}");
-        host.ends_with(&*self.host)
+        debug!("not_api_host_guard: uri_host {host:?}");
+        !host.eq_ignore_ascii_case(&self.api_host)
     }
 }
@@ -0,0 +1,59 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use actix_web::error::PayloadError;
+use actix_web::web::Bytes;
+use futures_util::Stream;
+use tokio::time::{Instant, Sleep};
+
+/// Fails an upstream body once no chunk arrived for `timeout`, instead of
+/// bounding its total duration, so event streams and other long-lived
+/// responses stay open as long as they keep sending.
+pub struct IdleTimeout<S> {
+    body: S,
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    timed_out: bool,
+}
+
+impl<S> IdleTimeout<S> {
+    pub fn new(body: S, timeout: Duration) -> Self {
+        Self {
+            body,
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+            timed_out: false,
+        }
+    }
+}
+
+impl<S> Stream for IdleTimeout<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.timed_out {
+            return Poll::Ready(None);
+        }
+        match Pin::new(&mut this.body).poll_next(cx) {
+            Poll::Ready(item) => {
+                this.sleep.as_mut().reset(Instant::now() + this.timeout);
+                Poll::Ready(item)
+            }
+            Poll::Pending if this.sleep.as_mut().poll(cx).is_ready() => {
+                this.timed_out = true;
+                Poll::Ready(Some(Err(PayloadError::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "upstream body idle for too long",
+                )))))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use std::net::IpAddr;
+
+use crate::connections::models::Connection;
+use crate::errors::AppError;
+use crate::util::parse_cidr;
+
+fn matches_any(cidrs: &[String], ip: IpAddr) -> bool {
+    cidrs
+        .iter()
+        .filter_map(|cidr| parse_cidr(cidr))
+        .any(|net| net.contains(&ip))
+}
+
+pub fn is_allowed(connection: &Connection, ip: IpAddr) -> bool {
+    if matches_any(&connection.denied_cidrs, ip) {
+        return false;
+    }
+    connection.allowed_cidrs.is_empty() || matches_any(&connection.allowed_cidrs, ip)
+}
+
+/// Deny entries take precedence over allow entries. An empty allow list lets
+/// every address through that is not explicitly denied.
+pub fn authorize(connection: &Connection, client_ip: Option<IpAddr>) -> Result<(), AppError> {
+    if connection.allowed_cidrs.is_empty() && connection.denied_cidrs.is_empty() {
+        return Ok(());
+    }
+
+    match client_ip {
+        Some(ip) if is_allowed(connection, ip) => Ok(()),
+        _ => Err(AppError::Forbidden),
+    }
+}
@@ -1,2 +1,20 @@
+pub mod admission;
+pub mod basic_auth;
+pub mod body_limit;
+pub mod cache;
+pub mod capture;
+mod client_ip;
+mod compression;
 pub mod controller;
-mod wildcard_host_guard;
+mod cors;
+mod forwarded;
+mod header_rules;
+mod host_guard;
+mod idle_timeout;
+mod ip_filter;
+mod offline;
+pub mod rate_limit;
+mod retry;
+mod sticky;
+mod trace_context;
+pub mod upstream;
@@ -0,0 +1,48 @@
+use actix_web::http::header::{LOCATION, RETRY_AFTER};
+use actix_web::HttpResponse;
+use askama::Template;
+use derive_more::Constructor;
+
+use crate::connections::models::{Connection, OfflineBehaviour};
+use crate::errors::{AppError, AppResponse};
+
+#[derive(Template, Constructor)]
+#[template(path = "maintenance.html")]
+struct MaintenanceView<'a> {
+    title: &'a str,
+    subdomain: &'a str,
+    message: &'a str,
+}
+
+/// Answers visitors of a connection without any client attached, following
+/// the connection's offline behaviour if it has one.
+pub fn respond(connection: &Connection) -> AppResponse {
+    let Some(offline_behaviour) = &connection.offline_behaviour else {
+        return Err(AppError::TunnelOffline {
+            subdomain: connection.subdomain.clone(),
+        });
+    };
+
+    match &offline_behaviour.0 {
+        OfflineBehaviour::Page { html } => Ok(HttpResponse::ServiceUnavailable()
+            .content_type("text/html; charset=utf-8")
+            .body(html.clone())),
+        OfflineBehaviour::Redirect { url } => Ok(HttpResponse::TemporaryRedirect()
+            .insert_header((LOCATION, url.as_str()))
+            .finish()),
+        OfflineBehaviour::Maintenance {
+            message,
+            retry_after_secs,
+        } => {
+            let message = message
+                .as_deref()
+                .unwrap_or("This service is temporarily unavailable, please come back later.");
+            let body =
+                MaintenanceView::new("Maintenance", &connection.subdomain, message).render()?;
+            Ok(HttpResponse::ServiceUnavailable()
+                .content_type("text/html")
+                .insert_header((RETRY_AFTER, retry_after_secs.to_string()))
+                .body(body))
+        }
+    }
+}
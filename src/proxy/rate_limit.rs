@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use uuid::Uuid;
+
+use crate::connections::models::{Connection, TokenBucket};
+use crate::errors::AppError;
+
+static RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+static RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+static RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// Past this many buckets, some are dropped before adding another.
+const MAX_BUCKETS: usize = 100_000;
+/// What an eviction leaves, so that the next one is many new visitors away.
+const BUCKETS_AFTER_EVICTION: usize = MAX_BUCKETS / 10 * 9;
+
+/// The connection, plus the visitor address for per-IP buckets.
+type Key = (Uuid, Option<IpAddr>);
+
+/// What is left of the tightest bucket, sent back as `RateLimit-*` headers.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again.
+    pub reset_secs: u64,
+    /// Seconds until the next request is let through.
+    pub retry_after_secs: u64,
+}
+
+impl Quota {
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(RATELIMIT_LIMIT.clone(), HeaderValue::from(self.limit));
+        headers.insert(
+            RATELIMIT_REMAINING.clone(),
+            HeaderValue::from(self.remaining),
+        );
+        headers.insert(RATELIMIT_RESET.clone(), HeaderValue::from(self.reset_secs));
+    }
+
+    fn tightest(quota: Option<Self>, other: Self) -> Self {
+        quota
+            .filter(|quota| quota.remaining <= other.remaining)
+            .unwrap_or(other)
+    }
+}
+
+struct Bucket {
+    config: TokenBucket,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn new(config: TokenBucket, now: Instant) -> Self {
+        Self {
+            config,
+            tokens: f64::from(config.burst),
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        self.tokens = elapsed
+            .mul_add(f64::from(self.config.requests_per_second), self.tokens)
+            .min(f64::from(self.config.burst));
+        self.updated_at = now;
+    }
+
+    /// Whether the bucket has refilled by `now`, leaving it untouched.
+    fn is_full_at(&self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.updated_at).as_secs_f64();
+        elapsed.mul_add(f64::from(self.config.requests_per_second), self.tokens)
+            >= f64::from(self.config.burst)
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn quota(&self) -> Quota {
+        let rate = f64::from(self.config.requests_per_second);
+        Quota {
+            limit: self.config.burst,
+            remaining: self.tokens.floor() as u32,
+            reset_secs: ((f64::from(self.config.burst) - self.tokens) / rate).ceil() as u64,
+            retry_after_secs: ((1.0 - self.tokens).max(0.0) / rate).ceil() as u64,
+        }
+    }
+}
+
+/// Drops the buckets that refilled, then the least recently used ones until
+/// `BUCKETS_AFTER_EVICTION` are left.
+fn evict(buckets: &mut HashMap<Key, Bucket>, now: Instant) {
+    buckets.retain(|_, bucket| !bucket.is_full_at(now));
+    if buckets.len() <= BUCKETS_AFTER_EVICTION {
+        return;
+    }
+    let excess = buckets.len() - BUCKETS_AFTER_EVICTION;
+    let mut used_at = buckets
+        .values()
+        .map(|bucket| bucket.updated_at)
+        .collect::<Vec<_>>();
+    let (_, newest_evicted, _) = used_at.select_nth_unstable(excess - 1);
+    let newest_evicted = *newest_evicted;
+    buckets.retain(|_, bucket| bucket.updated_at > newest_evicted);
+}
+
+/// Token buckets of every rate limited connection and visitor.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Takes a token from each bucket applying to the request, or none at all
+    /// if one of them is empty. Returns the quota to advertise, if the
+    /// connection is rate limited.
+    pub fn check(
+        &self,
+        connection: &Connection,
+        ip: Option<IpAddr>,
+    ) -> Result<Option<Quota>, AppError> {
+        let Some(rate_limit) = &connection.rate_limit else {
+            return Ok(None);
+        };
+        let keys = [
+            rate_limit
+                .per_connection
+                .map(|config| ((connection.id, None), config)),
+            rate_limit
+                .per_ip
+                .zip(ip)
+                .map(|(config, ip)| ((connection.id, Some(ip)), config)),
+        ];
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(None);
+        };
+        let now = Instant::now();
+        if buckets.len() >= MAX_BUCKETS {
+            evict(&mut buckets, now);
+        }
+
+        let mut exhausted = None;
+        for (key, config) in keys.iter().flatten() {
+            let bucket = buckets
+                .entry(*key)
+                .or_insert_with(|| Bucket::new(*config, now));
+            bucket.config = *config;
+            bucket.refill(now);
+            if bucket.tokens < 1.0 {
+                let quota = bucket.quota();
+                exhausted = exhausted
+                    .filter(|exhausted: &Quota| {
+                        exhausted.retry_after_secs >= quota.retry_after_secs
+                    })
+                    .or(Some(quota));
+            }
+        }
+        if let Some(quota) = exhausted {
+            return Err(AppError::RateLimited { quota });
+        }
+
+        let mut quota = None;
+        for (key, _) in keys.iter().flatten() {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+                quota = Some(Quota::tightest(quota, bucket.quota()));
+            }
+        }
+        Ok(quota)
+    }
+}
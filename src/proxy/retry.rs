@@ -0,0 +1,27 @@
+use std::time::Duration;
+
+use actix_web::http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use actix_web::HttpRequest;
+
+use crate::settings;
+
+/// Only requests with a configured method and without a body are retried,
+/// since the body has been streamed away by the first attempt.
+pub fn is_retryable(retry: &settings::Retry, req: &HttpRequest) -> bool {
+    let headers = req.headers();
+    let has_body = headers.contains_key(TRANSFER_ENCODING)
+        || headers
+            .get(CONTENT_LENGTH)
+            .is_some_and(|content_length| content_length.as_bytes() != b"0");
+    !has_body
+        && retry
+            .methods
+            .iter()
+            .any(|method| method.eq_ignore_ascii_case(req.method().as_str()))
+}
+
+/// Doubles the configured delay after each failed attempt, starting at 1.
+pub fn backoff(retry: &settings::Retry, attempt: u32) -> Duration {
+    Duration::from_millis(retry.backoff_ms)
+        .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+}
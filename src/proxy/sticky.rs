@@ -0,0 +1,42 @@
+use actix_web::cookie::Cookie;
+use actix_web::http::header::{HeaderMap, HeaderValue, COOKIE};
+use actix_web::{HttpRequest, HttpResponse};
+
+const COOKIE_NAME: &str = "exposed_upstream";
+
+pub fn forward_id(req: &HttpRequest) -> Option<u64> {
+    req.cookie(COOKIE_NAME)?.value().parse().ok()
+}
+
+/// Keeps the balancing cookie to ourselves, the tunnelled application never
+/// sees it.
+pub fn remove_cookie(headers: &mut HeaderMap) {
+    let cookies = headers
+        .get_all(COOKIE)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|cookie| {
+            !cookie.is_empty()
+                && !cookie
+                    .split_once('=')
+                    .is_some_and(|(name, _)| name == COOKIE_NAME)
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    headers.remove(COOKIE);
+    if let Ok(value) = HeaderValue::try_from(cookies) {
+        if !value.is_empty() {
+            headers.insert(COOKIE, value);
+        }
+    }
+}
+
+pub fn set_cookie(resp: &mut HttpResponse, forward_id: u64) {
+    let cookie = Cookie::build(COOKIE_NAME, forward_id.to_string())
+        .path("/")
+        .http_only(true)
+        .finish();
+    // Headers of the upstream are already valid, adding a cookie cannot fail.
+    let _ = resp.add_cookie(&cookie);
+}
@@ -0,0 +1,47 @@
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use uuid::Uuid;
+
+static TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+/// Splits a version 00 `traceparent` into trace id and flags, rejecting the
+/// all-zero ids the W3C Trace Context spec declares invalid.
+fn parse(traceparent: &str) -> Option<(&str, &str)> {
+    let mut fields = traceparent.trim().split('-');
+    let (version, trace_id, parent_id, flags) = (
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+    );
+    let is_hex = |field: &str, len: usize| {
+        field.len() == len
+            && field
+                .bytes()
+                .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let is_valid = version == "00"
+        && fields.next().is_none()
+        && is_hex(trace_id, 32)
+        && is_hex(parent_id, 16)
+        && is_hex(flags, 2)
+        && trace_id.bytes().any(|byte| byte != b'0')
+        && parent_id.bytes().any(|byte| byte != b'0');
+    is_valid.then_some((trace_id, flags))
+}
+
+/// Continues the visitor's trace, or starts one, with the proxy as parent of
+/// the upstream request. `tracestate` is passed along untouched.
+pub fn propagate(headers: &mut HeaderMap) {
+    let incoming = headers
+        .get(&TRACEPARENT)
+        .and_then(|traceparent| traceparent.to_str().ok())
+        .and_then(parse)
+        .map(|(trace_id, flags)| (trace_id.to_string(), flags.to_string()));
+    let (trace_id, flags) =
+        incoming.unwrap_or_else(|| (Uuid::new_v4().simple().to_string(), "00".to_string()));
+    let (span_id, _) = Uuid::new_v4().as_u64_pair();
+    if let Ok(traceparent) = HeaderValue::try_from(format!("00-{trace_id}-{span_id:016x}-{flags}"))
+    {
+        headers.insert(TRACEPARENT.clone(), traceparent);
+    }
+}
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use crate::settings::Upstream;
+
+/// Builds the client used to reach the forward listeners of tunnels. `awc`
+/// clients are not `Send`, so every worker builds its own and keeps its pool
+/// of keep-alive connections for the lifetime of the worker.
+///
+/// The client's own timeout, 5 seconds unless disabled, is left off: awc
+/// applies it to whole responses, cutting long downloads and event streams. The proxy bounds the
+/// response head with `timeout_secs` and bodies with `idle_timeout_secs`.
+pub fn client(settings: &Upstream) -> awc::Client {
+    let connector = awc::Connector::new()
+        .limit(settings.pool_size)
+        .conn_keep_alive(Duration::from_secs(settings.keep_alive_secs))
+        .conn_lifetime(Duration::from_secs(settings.conn_lifetime_secs));
+    awc::Client::builder()
+        .connector(connector)
+        .disable_timeout()
+        .disable_redirects()
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Bytes;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use futures_util::stream;
+
+    use super::*;
+
+    async fn slow_stream() -> HttpResponse {
+        let chunks = stream::unfold(0, |sent| async move {
+            match sent {
+                0 => Some((Ok::<_, actix_web::Error>(Bytes::from_static(b"before ")), 1)),
+                1 => {
+                    tokio::time::sleep(Duration::from_millis(1500)).await;
+                    Some((Ok(Bytes::from_static(b"after")), 2))
+                }
+                _ => None,
+            }
+        });
+        HttpResponse::Ok().streaming(chunks)
+    }
+
+    #[actix_web::test]
+    async fn streams_past_timeout() {
+        let server = HttpServer::new(|| App::new().route("/", web::get().to(slow_stream)))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        let settings = Upstream {
+            pool_size: 1,
+            keep_alive_secs: 15,
+            conn_lifetime_secs: 75,
+            timeout_secs: 1,
+            idle_timeout_secs: 60,
+        };
+        let mut resp = client(&settings)
+            .get(format!("http://{addr}/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.body().await.unwrap(), "before after");
+    }
+}
@@ -0,0 +1,256 @@
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+use crate::util::canonical_ip;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 header allowed by the spec, CRLF included.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_VERSION: u8 = 0x20;
+const V2_COMMAND_PROXY: u8 = 0x01;
+const V2_TCP_OVER_IPV4: u8 = 0x11;
+const V2_TCP_OVER_IPV6: u8 = 0x21;
+
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("PROXY protocol: {msg}"))
+}
+
+/// Parses the rest of a v1 line such as `TCP4 192.0.2.1 192.0.2.2 56324 443`.
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let mut fields = line.split(' ');
+    match fields.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4" | "TCP6") => {}
+        _ => return Err(invalid("unsupported v1 protocol")),
+    }
+    let source_ip = fields
+        .next()
+        .and_then(|ip| ip.parse::<IpAddr>().ok())
+        .ok_or_else(|| invalid("invalid v1 source address"))?;
+    let _destination_ip = fields.next();
+    let source_port = fields
+        .next()
+        .and_then(|port| port.parse::<u16>().ok())
+        .ok_or_else(|| invalid("invalid v1 source port"))?;
+    Ok(Some(SocketAddr::new(source_ip, source_port)))
+}
+
+/// Parses the address block following a v2 header.
+fn parse_v2(command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if command & 0xF0 != V2_VERSION {
+        return Err(invalid("unsupported v2 version"));
+    }
+    // LOCAL connections, such as health checks, speak for themselves.
+    if command & 0x0F != V2_COMMAND_PROXY {
+        return Ok(None);
+    }
+    let source = match family {
+        V2_TCP_OVER_IPV4 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            SocketAddr::new(IpAddr::V4(ip), port)
+        }
+        V2_TCP_OVER_IPV6 if addresses.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)
+        }
+        V2_TCP_OVER_IPV4 | V2_TCP_OVER_IPV6 => return Err(invalid("short v2 address")),
+        _ => return Ok(None),
+    };
+    Ok(Some(source))
+}
+
+async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Both versions are at least 12 bytes long, so this never reads into the
+    // proxied data.
+    let mut signature = [0; 12];
+    stream.read_exact(&mut signature).await?;
+
+    if signature == V2_SIGNATURE {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        let [command, family, len @ ..] = header;
+        let mut addresses = vec![0; usize::from(u16::from_be_bytes(len))];
+        stream.read_exact(&mut addresses).await?;
+        return parse_v2(command, family, &addresses);
+    }
+
+    if !signature.starts_with(V1_PREFIX) {
+        return Err(invalid("missing header"));
+    }
+    let mut line = signature.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])
+        .map_err(|_| invalid("v1 header is not ASCII"))?;
+    parse_v1(line)
+}
+
+/// Returns the client address of a stream. Trusted sources, such as a TCP
+/// load balancer, must open with a PROXY v1 or v2 header, which is consumed
+/// and takes precedence over the socket address. Other peers are taken at
+/// face value.
+pub async fn accept(
+    stream: &mut TcpStream,
+    trusted_sources: &[IpNet],
+) -> io::Result<Option<SocketAddr>> {
+    let peer_addr = stream.peer_addr()?;
+    let peer_ip = canonical_ip(peer_addr.ip());
+    if !trusted_sources.iter().any(|net| net.contains(&peer_ip)) {
+        return Ok(Some(peer_addr));
+    }
+
+    let source = tokio::time::timeout(HEADER_TIMEOUT, read_header(stream))
+        .await
+        .map_err(|_| io::Error::new(ErrorKind::TimedOut, "PROXY protocol: header timed out"))??;
+    Ok(Some(source.unwrap_or(peer_addr)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([command, family]);
+        header.extend(u16::try_from(addresses.len()).unwrap().to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[test]
+    fn parses_v1_addresses() {
+        assert_eq!(
+            parse_v1("TCP4 192.0.2.1 192.0.2.2 56324 443").unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            parse_v1("TCP6 2001:db8::1 2001:db8::2 56324 443").unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn ignores_v1_unknown() {
+        assert_eq!(parse_v1("UNKNOWN").unwrap(), None);
+        assert_eq!(
+            parse_v1("UNKNOWN 192.0.2.1 192.0.2.2 56324 443").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_v1() {
+        assert!(parse_v1("UDP4 192.0.2.1 192.0.2.2 56324 443").is_err());
+        assert!(parse_v1("TCP4 192.0.2 192.0.2.2 56324 443").is_err());
+        assert!(parse_v1("TCP4 192.0.2.1 192.0.2.2 65536 443").is_err());
+        assert!(parse_v1("TCP4 192.0.2.1").is_err());
+    }
+
+    #[test]
+    fn parses_v2_addresses() {
+        let ipv4 = [192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB];
+        assert_eq!(
+            parse_v2(0x21, V2_TCP_OVER_IPV4, &ipv4).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+
+        let mut ipv6 = [0; 36];
+        ipv6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[16..32].copy_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[32..].copy_from_slice(&[0xDC, 0x04, 0x01, 0xBB]);
+        assert_eq!(
+            parse_v2(0x21, V2_TCP_OVER_IPV6, &ipv6).unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn ignores_v2_local_and_unknown_families() {
+        assert_eq!(parse_v2(0x20, V2_TCP_OVER_IPV4, &[]).unwrap(), None);
+        assert_eq!(parse_v2(0x21, 0x00, &[]).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_v2() {
+        assert!(parse_v2(0x11, V2_TCP_OVER_IPV4, &[0; 12]).is_err());
+        assert!(parse_v2(0x21, V2_TCP_OVER_IPV4, &[0; 11]).is_err());
+        assert!(parse_v2(0x21, V2_TCP_OVER_IPV6, &[0; 35]).is_err());
+    }
+
+    #[test]
+    fn accepts_v2_addresses_followed_by_tlvs() {
+        let mut addresses = vec![192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB];
+        addresses.extend([0x04, 0x00, 0x01, 0x00]);
+        assert_eq!(
+            parse_v2(0x21, V2_TCP_OVER_IPV4, &addresses).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+    }
+
+    #[actix_web::test]
+    async fn reads_only_the_header() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+
+        let header = v2_header(
+            0x21,
+            V2_TCP_OVER_IPV4,
+            &[192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB],
+        );
+        let data = [header.as_slice(), b"SSH-2.0"].concat();
+        let mut stream = data.as_slice();
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"SSH-2.0");
+    }
+
+    #[actix_web::test]
+    async fn rejects_short_headers() {
+        let mut stream: &[u8] = b"PROXY TCP4";
+        let e = read_header(&mut stream).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+
+        let header = v2_header(0x21, V2_TCP_OVER_IPV4, &[0; 12]);
+        let mut stream = &header[..header.len() - 1];
+        let e = read_header(&mut stream).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[actix_web::test]
+    async fn rejects_oversized_v1_headers() {
+        let mut header = b"PROXY UNKNOWN ".to_vec();
+        header.resize(V1_MAX_LEN + 10, b'x');
+        header.extend(b"\r\n");
+        let mut stream = header.as_slice();
+        let e = read_header(&mut stream).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[actix_web::test]
+    async fn rejects_missing_headers() {
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        let e = read_header(&mut stream).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,70 @@
+use std::future::Future;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use uuid::Uuid;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+/// The header as the upstream client writes it in HTTP/1 heads.
+const HTTP1_HEADER_LINE: &[u8] = b"\r\nx-request-id: ";
+
+/// Ids set by the visitor or a proxy in front are kept as long as they are
+/// short printable tokens.
+fn is_valid(request_id: &HeaderValue) -> bool {
+    let bytes = request_id.as_bytes();
+    !bytes.is_empty() && bytes.len() <= MAX_REQUEST_ID_LEN && bytes.iter().all(u8::is_ascii_graphic)
+}
+
+pub fn get(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(&X_REQUEST_ID)
+        .and_then(|request_id| request_id.to_str().ok())
+}
+
+/// The id of a request the proxy sends through a forward, if this chunk of
+/// the stream holds its head.
+pub fn find_in_http1(chunk: &[u8]) -> Option<&str> {
+    let start = chunk
+        .windows(HTTP1_HEADER_LINE.len())
+        .position(|window| window == HTTP1_HEADER_LINE)?
+        + HTTP1_HEADER_LINE.len();
+    let len = chunk[start..].iter().position(|&byte| byte == b'\r')?;
+    std::str::from_utf8(&chunk[start..start + len]).ok()
+}
+
+/// Keeps the `X-Request-Id` of the request if it is valid or sets a new one,
+/// and returns it so it can be echoed in the response.
+pub fn ensure(headers: &mut HeaderMap) -> Option<HeaderValue> {
+    let request_id = headers
+        .get(&X_REQUEST_ID)
+        .filter(|request_id| is_valid(request_id))
+        .cloned()
+        .or_else(|| HeaderValue::try_from(Uuid::new_v4().to_string()).ok());
+    if let Some(request_id) = &request_id {
+        headers.insert(X_REQUEST_ID.clone(), request_id.clone());
+    }
+    request_id
+}
+
+/// Gives every request an `X-Request-Id`, before the logger and the proxy
+/// read it, and echoes it in the response.
+pub fn assign<S, B>(
+    mut req: ServiceRequest,
+    service: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let request_id = ensure(req.headers_mut());
+
+    let fut = service.call(req);
+    async move {
+        let mut res = fut.await?;
+        if let Some(request_id) = request_id {
+            res.headers_mut().insert(X_REQUEST_ID.clone(), request_id);
+        }
+        Ok(res)
+    }
+}
@@ -0,0 +1,93 @@
+use crate::connections::controller::{find_connection, normalize_hostname};
+use crate::connections::models::Connection;
+use crate::errors::{AppError, AppResponse};
+use crate::settings::Settings;
+use actix_web::{delete, get, post, web, HttpResponse};
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{dto, models::Route, views};
+
+fn normalize_path_prefix(path_prefix: &str) -> Result<String, AppError> {
+    let path_prefix = path_prefix.trim();
+    if !path_prefix.starts_with('/') || path_prefix.contains(['?', '#']) {
+        return Err(AppError::InvalidParams(format!(
+            "Invalid path prefix: {path_prefix}"
+        )));
+    }
+    let trimmed = path_prefix.trim_end_matches('/');
+    Ok(if trimmed.is_empty() { "/" } else { trimmed }.to_string())
+}
+
+/// Routes may be added on any hostname served by the proxy, the subdomain
+/// of any connection or any custom domain, so that one host can be split
+/// between several connections.
+async fn is_served(db: &PgPool, settings: &Settings, hostname: &str) -> Result<bool, AppError> {
+    match Connection::get_by_host(db, hostname, settings).await {
+        Ok(_) => Ok(true),
+        Err(sqlx::Error::RowNotFound) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[get("/{connection_id}/routes")]
+pub async fn index(db: web::Data<PgPool>, path: web::Path<String>) -> AppResponse {
+    let connection = find_connection(&db, path.into_inner()).await?;
+    let routes = Route::get_all_for_connection(&db, &connection.id).await?;
+    let route_views = routes.iter().map(dto::View::from).collect();
+    let index_view = views::IndexView::new(&route_views);
+    let body = serde_json::to_string(&index_view)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
+#[post("/{connection_id}/routes")]
+pub async fn create(
+    db: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    path: web::Path<String>,
+    params: web::Json<dto::Create>,
+) -> AppResponse {
+    let connection = find_connection(&db, path.into_inner()).await?;
+    let hostname = normalize_hostname(&params.hostname, &settings)?;
+    if !is_served(&db, &settings, &hostname).await? {
+        return Err(AppError::InvalidParams(format!(
+            "Hostname {hostname} is not served by any connection"
+        )));
+    }
+    let mut route = Route::new(
+        connection.id,
+        hostname,
+        normalize_path_prefix(&params.path_prefix)?,
+    );
+    route.strip_prefix = params.strip_prefix;
+    route.position = params.position;
+    route.insert(&db).await?;
+    let show_view = dto::ShowView::new(dto::View::from(&route));
+    let body = serde_json::to_string(&show_view)?;
+    Ok(HttpResponse::Created()
+        .content_type("application/json")
+        .body(body))
+}
+
+#[delete("/{connection_id}/routes/{route_id}")]
+pub async fn delete(db: web::Data<PgPool>, path: web::Path<(String, String)>) -> AppResponse {
+    let (connection_id, route_id) = path.into_inner();
+    let connection = find_connection(&db, connection_id).await?;
+    let route_id = Uuid::parse_str(&route_id).context("Failed to parse route UUID")?;
+    let route = Route::get(&db, &connection.id, &route_id)
+        .await
+        .map_err(|_| AppError::NotFound)?;
+    route.delete(&db).await?;
+    let show_view = dto::ShowView::new(dto::View::from(&route));
+    let body = serde_json::to_string(&show_view)?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
+pub fn urls(cfg: &mut web::ServiceConfig) {
+    cfg.service(index).service(create).service(delete);
+}
@@ -0,0 +1,42 @@
+use derive_more::Constructor;
+use serde::{Deserialize, Serialize};
+
+use super::models::Route;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct Create {
+    pub hostname: String,
+    pub path_prefix: String,
+    #[serde(default)]
+    pub strip_prefix: bool,
+    #[serde(default)]
+    pub position: i32,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct View {
+    pub id: String,
+    pub connection_id: String,
+    pub hostname: String,
+    pub path_prefix: String,
+    pub strip_prefix: bool,
+    pub position: i32,
+}
+
+impl From<&Route> for View {
+    fn from(route: &Route) -> Self {
+        Self {
+            id: route.id.to_string(),
+            connection_id: route.connection_id.to_string(),
+            hostname: route.hostname.clone(),
+            path_prefix: route.path_prefix.clone(),
+            strip_prefix: route.strip_prefix,
+            position: route.position,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Constructor)]
+pub struct ShowView {
+    pub route: View,
+}
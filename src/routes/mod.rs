@@ -0,0 +1,4 @@
+pub mod controller;
+mod dto;
+pub mod models;
+mod views;
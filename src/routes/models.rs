@@ -0,0 +1,103 @@
+pub use sqlx::types::Uuid;
+use sqlx::{FromRow, PgPool, Result};
+
+/// Sends requests for `hostname` whose path starts with `path_prefix` to the
+/// connection. Routes of a hostname are tried by ascending `position`.
+#[derive(FromRow)]
+pub struct Route {
+    pub id: Uuid,
+    pub connection_id: Uuid,
+    pub hostname: String,
+    pub path_prefix: String,
+    pub strip_prefix: bool,
+    pub position: i32,
+}
+
+impl Route {
+    pub fn new(connection_id: Uuid, hostname: String, path_prefix: String) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            connection_id,
+            hostname,
+            path_prefix,
+            strip_prefix: false,
+            position: 0,
+        }
+    }
+
+    /// Prefixes match whole path segments: `/api` matches `/api` and
+    /// `/api/users` but not `/apis`.
+    pub fn matches(&self, path: &str) -> bool {
+        if self.path_prefix == "/" {
+            return true;
+        }
+        path.strip_prefix(&*self.path_prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Returns the path to forward, without the prefix if `strip_prefix` is set.
+    pub fn forwarded_path<'a>(&self, path: &'a str) -> &'a str {
+        if !self.strip_prefix || self.path_prefix == "/" {
+            return path;
+        }
+        match path.strip_prefix(&*self.path_prefix) {
+            Some("") => "/",
+            Some(rest) => rest,
+            None => path,
+        }
+    }
+
+    pub async fn insert(&self, pool: &PgPool) -> Result<()> {
+        // language=PostgreSQL
+        sqlx::query("INSERT INTO routes (id, connection_id, hostname, path_prefix, strip_prefix, position) VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(self.id)
+            .bind(self.connection_id)
+            .bind(&self.hostname)
+            .bind(&self.path_prefix)
+            .bind(self.strip_prefix)
+            .bind(self.position)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_all_for_connection(pool: &PgPool, connection_id: &Uuid) -> Result<Vec<Self>> {
+        // language=PostgreSQL
+        sqlx::query_as(
+            "SELECT * FROM routes WHERE connection_id = $1 ORDER BY hostname, position, path_prefix",
+        )
+        .bind(connection_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn get_all_for_hostname(pool: &PgPool, hostname: &str) -> Result<Vec<Self>> {
+        // language=PostgreSQL
+        sqlx::query_as(
+            "SELECT * FROM routes WHERE hostname = $1 ORDER BY position, length(path_prefix) DESC, path_prefix",
+        )
+        .bind(hostname)
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn get(pool: &PgPool, connection_id: &Uuid, id: &Uuid) -> Result<Self> {
+        // language=PostgreSQL
+        sqlx::query_as("SELECT * FROM routes WHERE connection_id = $1 AND id = $2")
+            .bind(connection_id)
+            .bind(id)
+            .fetch_one(pool)
+            .await
+    }
+
+    pub async fn delete(&self, pool: &PgPool) -> Result<()> {
+        // language=PostgreSQL
+        sqlx::query("DELETE FROM routes WHERE id = $1")
+            .bind(self.id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::Result;
 use config::Config;
+use ipnet::IpNet;
 use serde::Deserialize;
 use std::env;
 use url::Url;
@@ -17,19 +18,98 @@ pub struct Http {
     pub secure: bool,
     pub secret: String,
     pub vhost_suffix: String,
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    #[serde(default)]
+    pub h2c: bool,
+    pub max_body_size: usize,
+}
+
+/// Peers, such as TCP load balancers, that open every connection with a
+/// PROXY protocol header.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProxyProtocol {
+    pub trusted_sources: Vec<IpNet>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Sshd {
     pub server_port: u16,
     pub server_key: String,
+    pub reconnect_grace_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Tls {
+    pub bind_port: u16,
+    pub cert_path: String,
+    pub key_path: String,
+    pub certs_dir: Option<String>,
+    /// Zero only loads certificates at startup.
+    pub reload_interval_secs: u64,
+}
+
+/// gRPC is only served on its own listener, on `bind_port`. The HTTP
+/// listeners answer gRPC calls with `UNIMPLEMENTED` pointing there.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Grpc {
+    pub enabled: bool,
+    pub bind_port: u16,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Acme {
+    pub enabled: bool,
+    pub directory_url: String,
+    pub contact_email: Option<String>,
+    pub renew_before_days: i64,
+    pub check_interval_secs: u64,
+    pub dns01_hook: Option<String>,
+    /// PEM roots to trust instead of the system ones, for test CAs.
+    pub ca_bundle_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Upstream {
+    pub pool_size: usize,
+    pub keep_alive_secs: u64,
+    pub conn_lifetime_secs: u64,
+    pub timeout_secs: u64,
+    pub idle_timeout_secs: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Retry {
+    pub methods: Vec<String>,
+    pub attempts: u32,
+    pub backoff_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Capture {
+    pub max_requests: i64,
+    pub max_body_size: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Cache {
+    pub max_size: usize,
+    pub max_entry_size: usize,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
     pub database: Database,
     pub http: Http,
+    pub proxy_protocol: ProxyProtocol,
     pub sshd: Sshd,
+    pub tls: Tls,
+    pub grpc: Grpc,
+    pub acme: Acme,
+    pub upstream: Upstream,
+    pub retry: Retry,
+    pub capture: Capture,
+    pub cache: Cache,
 }
 
 impl Settings {
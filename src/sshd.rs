@@ -4,19 +4,38 @@ use anyhow::Result;
 use async_trait::async_trait;
 use russh::server::{self, Auth, Handle, Session};
 use sqlx::PgPool;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{debug, info, warn};
 use subtle::ConstantTimeEq;
+use uuid::Uuid;
 
 use crate::{
-    connections::models::Connection, errors::StaticError, settings::Settings,
-    util::extract_subdomain,
+    connections::models::Connection,
+    errors::StaticError,
+    forwards::{Forward, ForwardRegistry},
+    proxy_protocol,
+    request_id,
+    settings::Settings,
 };
 
 struct TcpIpForwardTask {
     cancellation_token: CancellationToken,
     join_handle: tokio::task::JoinHandle<Result<()>>,
+    registry: Arc<ForwardRegistry>,
+    connection_id: Uuid,
+    forward_id: u64,
+}
+
+/// Sessions can end without cancelling their forward, so the listener and the
+/// registry entry are released whenever the task goes away.
+impl Drop for TcpIpForwardTask {
+    fn drop(&mut self) {
+        self.cancellation_token.cancel();
+        self.registry
+            .unregister(&self.connection_id, self.forward_id);
+    }
 }
 
 pub struct Server {
@@ -25,6 +44,7 @@ pub struct Server {
     server_pubkey: Arc<russh_keys::key::PublicKey>,
     id: usize,
     db: Arc<PgPool>,
+    registry: Arc<ForwardRegistry>,
     tcpip_forward_task: Option<TcpIpForwardTask>,
 }
 
@@ -36,13 +56,18 @@ impl Clone for Server {
             server_pubkey: self.server_pubkey.clone(),
             id: self.id,
             db: self.db.clone(),
+            registry: self.registry.clone(),
             tcpip_forward_task: None,
         }
     }
 }
 
 impl Server {
-    pub fn new(settings: Settings, db: PgPool) -> Result<Self, StaticError> {
+    pub fn new(
+        settings: Settings,
+        db: PgPool,
+        registry: Arc<ForwardRegistry>,
+    ) -> Result<Self, StaticError> {
         let server_key = russh_keys::decode_secret_key(&settings.sshd.server_key, None)?;
         let pub_key = server_key.clone_public_key()?;
         let config = russh::server::Config {
@@ -58,24 +83,47 @@ impl Server {
             server_pubkey: Arc::new(pub_key),
             id: 0,
             db: Arc::new(db),
+            registry,
             tcpip_forward_task: None,
         })
     }
 
-    pub async fn start(self, cancellation_token: CancellationToken) -> Result<()> {
+    /// Accepts sessions like `russh::server::run`, reading the PROXY protocol
+    /// header of trusted load balancers first.
+    pub async fn start(mut self, cancellation_token: CancellationToken) -> Result<()> {
         info!(
             "sshd server key fingerprint: {}",
             self.server_pubkey.fingerprint()
         );
 
         let bind_addr = format!("0.0.0.0:{}", self.settings.sshd.server_port);
-        tokio::select! {
-            res = russh::server::run(self.config.clone(), bind_addr, self) => {
-                res.map_err(Into::into)
-            },
-            _ = cancellation_token.cancelled() => {
-                Ok(())
-            }
+        let listener = TcpListener::bind(bind_addr).await?;
+        let trusted_sources = Arc::new(self.settings.proxy_protocol.trusted_sources.clone());
+        loop {
+            let (mut stream, peer_addr) = tokio::select! {
+                res = listener.accept() => res?,
+                _ = cancellation_token.cancelled() => return Ok(()),
+            };
+            // The handler is made once the PROXY header told who the client is.
+            let mut factory = self.clone();
+            self.id += 1;
+            let config = self.config.clone();
+            let trusted_sources = trusted_sources.clone();
+            tokio::spawn(async move {
+                let client_addr = match proxy_protocol::accept(&mut stream, &trusted_sources).await
+                {
+                    Ok(client_addr) => client_addr.unwrap_or(peer_addr),
+                    Err(e) => {
+                        warn!("Rejected ssh connection from {peer_addr}: {e}");
+                        return;
+                    }
+                };
+                debug!("ssh connection from {client_addr}");
+                let handler = server::Server::new_client(&mut factory, Some(client_addr));
+                if let Err(e) = russh::server::run_stream(config, stream, handler).await {
+                    debug!("ssh session from {client_addr} failed: {e:#}");
+                }
+            });
         }
     }
 }
@@ -112,8 +160,7 @@ impl server::Handler for Server {
         port: &mut u32,
         session: Session,
     ) -> Result<(Self, bool, Session), Self::Error> {
-        let subdomain = extract_subdomain(address, &self.settings)?;
-        let mut connection = Connection::get_by_subdomain(&self.db, &subdomain).await?;
+        let mut connection = Connection::get_by_host(&self.db, address, &self.settings).await?;
 
         let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}")).await?;
         let address = address.to_owned();
@@ -122,6 +169,8 @@ impl server::Handler for Server {
 
         connection.proxy_port = Some(port.to_string());
         connection.save(&self.db).await?;
+        let forward = self.registry.register(connection.id, listen_addr.port());
+        let forward_id = forward.id;
 
         let client_handle = session.handle();
         let cancellation_token = CancellationToken::new();
@@ -135,6 +184,7 @@ impl server::Handler for Server {
                                 tokio::task::spawn(tcpip_forward_stream_handler(
                                     address.clone(),
                                     listen_addr.port(),
+                                    forward.clone(),
                                     client_handle.clone(),
                                     tcp_stream,
                                     addr,
@@ -154,6 +204,9 @@ impl server::Handler for Server {
         self.tcpip_forward_task = Some(TcpIpForwardTask {
             cancellation_token,
             join_handle,
+            registry: self.registry.clone(),
+            connection_id: connection.id,
+            forward_id,
         });
         Ok((self, true, session))
     }
@@ -164,14 +217,16 @@ impl server::Handler for Server {
         _port: u32,
         session: Session,
     ) -> Result<(Self, bool, Session), Self::Error> {
-        if let Some(forward_task) = self.tcpip_forward_task.take() {
-            let subdomain = extract_subdomain(address, &self.settings)?;
-            let mut connection = Connection::get_by_subdomain(&self.db, &subdomain).await?;
-            connection.proxy_port = None;
-            connection.save(&self.db).await?;
-
+        if let Some(mut forward_task) = self.tcpip_forward_task.take() {
             forward_task.cancellation_token.cancel();
-            forward_task.join_handle.await??;
+            (&mut forward_task.join_handle).await??;
+            drop(forward_task);
+
+            let mut connection = Connection::get_by_host(&self.db, address, &self.settings).await?;
+            if !self.registry.is_online(&connection.id) {
+                connection.proxy_port = None;
+                connection.save(&self.db).await?;
+            }
             Ok((self, true, session))
         } else {
             Ok((self, false, session))
@@ -182,6 +237,7 @@ impl server::Handler for Server {
 async fn tcpip_forward_stream_handler(
     local_addr: String,
     local_port: u16,
+    forward: Arc<Forward>,
     client_handle: Handle,
     mut tcp_stream: TcpStream,
     addr: SocketAddr,
@@ -194,11 +250,43 @@ async fn tcpip_forward_stream_handler(
             remote_addr.to_string(),
             remote_port.into(),
         )
-        .await?;
-    let mut channel = channel.into_stream();
+        .await;
+    let channel = match channel {
+        Ok(channel) => {
+            forward.mark_available();
+            channel
+        }
+        Err(e) => {
+            warn!("Failed to open channel for {local_addr}, marking it unavailable: {e}");
+            forward.mark_unavailable();
+            return Err(e.into());
+        }
+    };
+    debug!("forwarded stream from {addr} opened for {local_addr}:{local_port}");
 
-    tokio::io::copy_bidirectional(&mut tcp_stream, &mut channel)
-        .await
-        .and(Ok(()))
-        .map_err(Into::into)
+    // Copied by hand rather than with copy_bidirectional, to log the id of
+    // every request going through, as the proxy logs it with the forward.
+    let (mut tcp_read, mut tcp_write) = tcp_stream.split();
+    let (mut channel_read, mut channel_write) = tokio::io::split(channel.into_stream());
+    let to_channel = async {
+        let mut buf = vec![0; 8192];
+        loop {
+            let len = tcp_read.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+            if let Some(request_id) = request_id::find_in_http1(&buf[..len]) {
+                debug!("request {request_id} carried by forwarded stream from {addr} through forward {}", forward.id);
+            }
+            channel_write.write_all(&buf[..len]).await?;
+        }
+        channel_write.shutdown().await
+    };
+    let to_tcp = async {
+        tokio::io::copy(&mut channel_read, &mut tcp_write).await?;
+        tcp_write.shutdown().await
+    };
+    let result = tokio::try_join!(to_channel, to_tcp);
+    debug!("forwarded stream from {addr} closed for {local_addr}:{local_port}");
+    result.and(Ok(())).map_err(Into::into)
 }
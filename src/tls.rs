@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey};
+use rustls_pemfile::Item;
+use sqlx::types::chrono::{DateTime, TimeZone, Utc};
+use sqlx::PgPool;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+
+use crate::domains::models::CustomDomain;
+use crate::settings::Settings;
+
+pub fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut &*cert_pem)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found"));
+    }
+    let key = rustls_pemfile::read_all(&mut &*key_pem)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .context("No private key found")?;
+    let signing_key =
+        sign::any_supported_type(&key).map_err(|_| anyhow!("Unsupported private key type"))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// When the first certificate of a PEM chain stops being valid.
+pub fn certificate_expiry(certificate_pem: &str) -> Result<DateTime<Utc>> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(certificate_pem.as_bytes())
+        .map_err(|e| anyhow!("Invalid certificate PEM: {e:?}"))?;
+    let certificate = pem
+        .parse_x509()
+        .map_err(|e| anyhow!("Invalid certificate: {e:?}"))?;
+    Utc.timestamp_opt(certificate.validity().not_after.timestamp(), 0)
+        .single()
+        .context("Invalid certificate expiry")
+}
+
+async fn certified_key_from_files(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let cert_pem = tokio::fs::read(cert_path)
+        .await
+        .with_context(|| format!("Failed to read {}", cert_path.display()))?;
+    let key_pem = tokio::fs::read(key_path)
+        .await
+        .with_context(|| format!("Failed to read {}", key_path.display()))?;
+    certified_key(&cert_pem, &key_pem)
+        .with_context(|| format!("Invalid certificate {}", cert_path.display()))
+}
+
+/// Reads `<hostname>.crt` / `<hostname>.key` pairs from `certs_dir`.
+async fn certified_keys_from_dir(dir: &Path) -> Result<HashMap<String, Arc<CertifiedKey>>> {
+    let mut keys = HashMap::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(keys),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let cert_path = entry.path();
+        if !cert_path.extension().is_some_and(|ext| ext == "crt") {
+            continue;
+        }
+        let Some(hostname) = cert_path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        let key_path = cert_path.with_extension("key");
+        match certified_key_from_files(&cert_path, &key_path).await {
+            Ok(key) => {
+                keys.insert(hostname.to_ascii_lowercase(), Arc::new(key));
+            }
+            Err(e) => warn!("Skipping certificate for {hostname}: {e:#}"),
+        }
+    }
+    Ok(keys)
+}
+
+/// Picks the certificate matching the SNI server name, falling back to the
+/// wildcard certificate of `vhost_suffix`.
+#[derive(Default)]
+pub struct CertResolver {
+    wildcard: RwLock<Option<Arc<CertifiedKey>>>,
+    domains: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub async fn reload(&self, settings: &Settings, db: &PgPool) -> Result<()> {
+        let tls = &settings.tls;
+        let wildcard =
+            certified_key_from_files(Path::new(&tls.cert_path), Path::new(&tls.key_path)).await?;
+
+        let mut domains = match &tls.certs_dir {
+            Some(certs_dir) => certified_keys_from_dir(Path::new(certs_dir)).await?,
+            None => HashMap::new(),
+        };
+        for custom_domain in CustomDomain::get_all_with_certificates(db).await? {
+            let (Some(cert_pem), Some(key_pem)) = (
+                &custom_domain.certificate_pem,
+                &custom_domain.private_key_pem,
+            ) else {
+                continue;
+            };
+            match certified_key(cert_pem.as_bytes(), key_pem.as_bytes()) {
+                Ok(key) => {
+                    domains.insert(custom_domain.hostname.clone(), Arc::new(key));
+                }
+                Err(e) => warn!(
+                    "Skipping stored certificate for {}: {e:#}",
+                    custom_domain.hostname
+                ),
+            }
+        }
+
+        debug!("loaded {} custom domain certificates", domains.len());
+        *self
+            .wildcard
+            .write()
+            .map_err(|_| anyhow!("Poisoned lock"))? = Some(Arc::new(wildcard));
+        *self.domains.write().map_err(|_| anyhow!("Poisoned lock"))? = domains;
+        Ok(())
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> rustls::ServerConfig {
+        rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+
+    pub async fn reload_periodically(
+        self: Arc<Self>,
+        settings: Settings,
+        db: PgPool,
+        cancellation_token: CancellationToken,
+    ) -> Result<()> {
+        if settings.tls.reload_interval_secs == 0 {
+            debug!("TLS certificate reload disabled");
+            return Ok(());
+        }
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(settings.tls.reload_interval_secs));
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = self.reload(&settings, &db).await {
+                        error!("Failed to reload TLS certificates, keeping the previous ones: {e:#}");
+                    }
+                },
+                _ = cancellation_token.cancelled() => {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if let Some(server_name) = client_hello.server_name() {
+            let server_name = server_name.to_ascii_lowercase();
+            if let Some(key) = self.domains.read().ok()?.get(&server_name) {
+                return Some(key.clone());
+            }
+        }
+        self.wildcard.read().ok()?.clone()
+    }
+}
@@ -1,4 +1,7 @@
+use std::net::IpAddr;
+
 use anyhow::{anyhow, Context, Result};
+use ipnet::IpNet;
 
 use crate::settings::Settings;
 
@@ -10,3 +13,18 @@ pub fn extract_subdomain(host: &str, settings: &Settings) -> Result<String> {
         .map(ToOwned::to_owned)
         .ok_or_else(|| anyhow!("No subdomain"))
 }
+
+pub fn parse_cidr(value: &str) -> Option<IpNet> {
+    value
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+        .map(|net| net.trunc())
+}
+
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}